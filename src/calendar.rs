@@ -0,0 +1,201 @@
+use crate::report::{self, ShowEpisodes};
+use crate::tvmaze::EpisodeResult;
+use chrono::{DateTime, NaiveDate, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::PathBuf;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct CalendarConfig {
+    /// iCalendar file of the upcoming episodes, rewritten by the service
+    #[serde(default)]
+    pub path: Option<PathBuf>,
+    /// Report the episodes that aired this number of days ago and have still not arrived
+    #[serde(default)]
+    pub overdue_days: Option<i64>,
+    /// Hours between two refreshes of the calendar and checks of the overdue episodes
+    #[serde(default = "CalendarConfig::default_interval")]
+    pub interval: u64,
+}
+
+impl CalendarConfig {
+    fn default_interval() -> u64 {
+        24
+    }
+
+    /// Check if the service has to refresh the calendar or check the overdue episodes
+    pub fn is_enabled(&self) -> bool {
+        self.path.is_some() || self.overdue_days.is_some()
+    }
+}
+
+impl Default for CalendarConfig {
+    fn default() -> Self {
+        CalendarConfig {
+            path: None,
+            overdue_days: None,
+            interval: Self::default_interval(),
+        }
+    }
+}
+
+fn air_time(episode: &EpisodeResult) -> Option<DateTime<Utc>> {
+    let stamp = episode.airstamp.as_deref()?;
+    DateTime::parse_from_rfc3339(stamp)
+        .ok()
+        .map(|t| t.with_timezone(&Utc))
+}
+
+fn air_date(episode: &EpisodeResult) -> Option<NaiveDate> {
+    NaiveDate::parse_from_str(episode.airdate.as_deref()?, "%Y-%m-%d").ok()
+}
+
+/// Escape a text value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Fold a content line in lines of at most 75 octets
+fn fold(line: &str) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+
+    folded.push_str("\r\n");
+    folded
+}
+
+/// Build an iCalendar of the episodes of the given shows that air after now.
+/// Episodes with an air time last their runtime, the others are all day events.
+pub fn ics(shows: &[ShowEpisodes], now: DateTime<Utc>) -> String {
+    let mut lines = vec![
+        String::from("BEGIN:VCALENDAR"),
+        String::from("VERSION:2.0"),
+        String::from("PRODID:-//media-sort-rs//Upcoming episodes//EN"),
+        String::from("CALSCALE:GREGORIAN"),
+        String::from("X-WR-CALNAME:Upcoming episodes"),
+    ];
+
+    for show in shows {
+        for episode in &show.episodes {
+            let number = match episode.number {
+                Some(n) => n,
+                None => continue,
+            };
+
+            let start = match (air_time(episode), air_date(episode)) {
+                (Some(t), _) if t >= now => vec![
+                    format!("DTSTART:{}", t.format("%Y%m%dT%H%M%SZ")),
+                    format!("DURATION:PT{}M", episode.runtime.unwrap_or(30)),
+                ],
+                (None, Some(d)) if d >= now.date_naive() => {
+                    vec![format!("DTSTART;VALUE=DATE:{}", d.format("%Y%m%d"))]
+                }
+                _ => continue,
+            };
+
+            let uid = match episode.id {
+                Some(id) => format!("tvmaze-episode-{id}@media-sort-rs"),
+                None => format!(
+                    "tvmaze-{}-s{}e{number}@media-sort-rs",
+                    show.tvmaze_id.unwrap_or(0),
+                    episode.season
+                ),
+            };
+
+            lines.push(String::from("BEGIN:VEVENT"));
+            lines.push(format!("UID:{uid}"));
+            lines.push(format!("DTSTAMP:{}", now.format("%Y%m%dT%H%M%SZ")));
+            lines.extend(start);
+            lines.push(format!(
+                "SUMMARY:{}",
+                escape(&format!(
+                    "{} - S{:02}E{number:02} - {}",
+                    show.show, episode.season, episode.name
+                ))
+            ));
+            lines.push(String::from("END:VEVENT"));
+        }
+    }
+
+    lines.push(String::from("END:VCALENDAR"));
+    lines.iter().map(|l| fold(l)).collect()
+}
+
+/// Get the episodes that aired at least days before today and have not arrived. Only the
+/// episodes after the last one in the show folder are expected to arrive: the older gaps are
+/// reported by the missing episodes report.
+pub fn overdue(show: &ShowEpisodes, today: NaiveDate, days: i64) -> Vec<&EpisodeResult> {
+    let present = report::present_episodes(&show.path);
+    let last = match present.iter().max() {
+        Some(l) => *l,
+        None => return vec![],
+    };
+
+    show.episodes
+        .iter()
+        .filter(|e| matches!(e.number, Some(n) if (e.season, n) > last))
+        .filter(|e| matches!(air_date(e), Some(d) if (today - d).num_days() >= days))
+        .collect()
+}
+
+#[cfg(test)]
+mod calendar_tests {
+    use crate::calendar::{fold, ics};
+    use crate::report::ShowEpisodes;
+    use crate::tvmaze::EpisodeResult;
+    use chrono::{TimeZone, Utc};
+    use std::path::PathBuf;
+
+    #[test]
+    fn check_ics() {
+        let episode = |id, number, airstamp: Option<&str>, airdate: &str| EpisodeResult {
+            id: Some(id),
+            name: String::from("Pilot, part 1"),
+            season: 1,
+            number: Some(number),
+            airdate: Some(airdate.to_string()),
+            airstamp: airstamp.map(String::from),
+            runtime: Some(60),
+        };
+        let show = ShowEpisodes {
+            show: String::from("The Show"),
+            path: PathBuf::from("/shows/The Show"),
+            tvmaze_id: Some(1),
+            episodes: vec![
+                episode(10, 1, Some("2024-01-01T02:00:00+00:00"), "2023-12-31"),
+                episode(11, 2, Some("2024-02-01T02:00:00+00:00"), "2024-01-31"),
+                episode(12, 3, None, "2024-03-01"),
+            ],
+            error: None,
+        };
+
+        let now = Utc.with_ymd_and_hms(2024, 1, 15, 0, 0, 0).unwrap();
+        let ics = ics(&[show], now);
+        assert!(!ics.contains("tvmaze-episode-10@"));
+        assert!(ics.contains(
+            "UID:tvmaze-episode-11@media-sort-rs\r\nDTSTAMP:20240115T000000Z\r\n\
+            DTSTART:20240201T020000Z\r\nDURATION:PT60M\r\n\
+            SUMMARY:The Show - S01E02 - Pilot\\, part 1\r\n"
+        ));
+        assert!(ics.contains("DTSTART;VALUE=DATE:20240301\r\n"));
+        assert!(ics.ends_with("END:VCALENDAR\r\n"));
+
+        let long = "X".repeat(100);
+        assert_eq!(
+            fold(&long),
+            format!("{}\r\n {}\r\n", &long[..75], &long[75..])
+        );
+    }
+}
@@ -1,8 +1,17 @@
+use crate::calendar::CalendarConfig;
+use crate::index::IndexConfig;
+use crate::mediainfo::{Episode, MediaInfo, MediaType, TVShowInfo};
+use crate::naming::{Field, Template};
+use crate::pool::WorkersConfig;
+use crate::sanitize::Sanitize;
+use crate::settle::SettleConfig;
+use crate::transfer::TransferMode;
 use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
-#[derive(Serialize, Deserialize)]
+#[derive(Serialize, Deserialize, Clone)]
 pub struct PermissionConfig {
     pub mode: u32,
     pub user: String,
@@ -14,22 +23,453 @@ pub struct OmdbConfig {
     pub apikey: String,
 }
 
+/// Templates used to build the destination of sorted files.
+/// Episodes are placed in `show_path/<show_folder>/<episode>` and movies in `movie_path/<movie>`,
+/// or in `movie_path/<movie_folder>/<movie>` if movie_folder is set.
 #[derive(Serialize, Deserialize)]
-pub struct Config {
-    pub dir_watch: PathBuf,
+pub struct NamingConfig {
+    #[serde(default = "NamingConfig::default_show_folder")]
+    pub show_folder: Template,
+    #[serde(default = "NamingConfig::default_episode")]
+    pub episode: Template,
+    #[serde(default = "NamingConfig::default_movie")]
+    pub movie: Template,
+    #[serde(default)]
+    pub movie_folder: Option<Template>,
+    #[serde(default)]
+    pub sanitize: Sanitize,
+}
+
+impl NamingConfig {
+    fn default_show_folder() -> Template {
+        Template::parse("{title}< ({year})>").unwrap()
+    }
+
+    fn default_episode() -> Template {
+        Template::parse("Season {season:02}/{title} - S{season:02}E{episode}").unwrap()
+    }
+
+    fn default_movie() -> Template {
+        Template::parse("{title}< ({year})>").unwrap()
+    }
+
+    fn validate(&self) -> Result<()> {
+        self.show_folder.validate(
+            "show_folder",
+            &[Field::Title, Field::Year, Field::ImdbId, Field::TvmazeId],
+            true,
+        )?;
+        self.episode.validate(
+            "episode",
+            &[
+                Field::Title,
+                Field::Year,
+                Field::Season,
+                Field::Episode,
+                Field::EpisodeTitle,
+                Field::Resolution,
+                Field::Edition,
+                Field::ImdbId,
+                Field::TvmazeId,
+            ],
+            false,
+        )?;
+        if !self.episode.fields().contains(&Field::Episode) {
+            bail!("Template episode must use {{episode}}");
+        }
+        self.validate_episode_name()?;
+        let movie_fields = [
+            Field::Title,
+            Field::Year,
+            Field::Resolution,
+            Field::Edition,
+            Field::ImdbId,
+        ];
+        if let Some(folder) = &self.movie_folder {
+            folder.validate("movie_folder", &movie_fields, true)?;
+        }
+        self.movie.validate("movie", &movie_fields, false)
+    }
+
+    /// Check that the episodes named by the template are recognized in the library (missing
+    /// episodes, scan): their file names must contain the season and episode as S01E02.
+    fn validate_episode_name(&self) -> Result<()> {
+        let sample = MediaInfo {
+            name: String::from("Title"),
+            year: Some(2001),
+            show_info: Some(TVShowInfo {
+                season: 12,
+                episode: Episode::Numbered(34),
+                title: Some(String::from("Episode Title")),
+            }),
+            resolution: Some(String::from("1080p")),
+            edition: Some(String::from("Extended")),
+            imdb_id: Some(String::from("tt0000001")),
+            tvmaze_id: Some(1),
+        };
+
+        let mut name = self
+            .episode
+            .render(&sample, self.sanitize)?
+            .into_os_string();
+        name.push(".mkv");
+        let found = MediaInfo::extract_media_info(Path::new(&name))
+            .show_info
+            .map(|s| (s.season, s.episode));
+        if found != Some((12, Episode::Numbered(34))) {
+            bail!(
+                "Template episode must name the files with S{{season}}E{{episode}}, e.g. {:?} \
+                is not recognized as S12E34",
+                Path::new(&name).file_name().unwrap_or_default()
+            );
+        }
+
+        Ok(())
+    }
+}
+
+impl Default for NamingConfig {
+    fn default() -> Self {
+        NamingConfig {
+            show_folder: Self::default_show_folder(),
+            episode: Self::default_episode(),
+            movie: Self::default_movie(),
+            movie_folder: None,
+            sanitize: Sanitize::default(),
+        }
+    }
+}
+
+/// A watched folder as written in the configuration file: either a path, or a path with a
+/// polling interval in seconds for folders where inotify does not work (e.g. network mounts).
+#[derive(Serialize, Deserialize, Clone)]
+#[serde(untagged)]
+pub enum WatchDirConfig {
+    Path(PathBuf),
+    Full {
+        path: PathBuf,
+        #[serde(default)]
+        poll: Option<u64>,
+    },
+}
+
+impl WatchDirConfig {
+    fn resolve(&self) -> WatchDir {
+        match self {
+            WatchDirConfig::Path(path) => WatchDir {
+                path: path.clone(),
+                poll: None,
+            },
+            WatchDirConfig::Full { path, poll } => WatchDir {
+                path: path.clone(),
+                poll: poll.map(Duration::from_secs),
+            },
+        }
+    }
+}
+
+pub struct WatchDir {
+    pub path: PathBuf,
+    /// Poll the folder at this interval instead of using inotify
+    pub poll: Option<Duration>,
+}
+
+/// A library as written in the configuration file.
+/// Unset permissions, overwrite policy and transfer mode are taken from the top level
+/// configuration.
+#[derive(Serialize, Deserialize)]
+pub struct LibraryConfig {
+    pub name: String,
+    pub dir_watch: Vec<WatchDirConfig>,
+    pub show_path: PathBuf,
+    pub movie_path: PathBuf,
+    #[serde(default)]
+    pub media_type: Option<MediaType>,
+    #[serde(default)]
+    pub permissions: Option<PermissionConfig>,
+    #[serde(default)]
+    pub overwrite: Option<bool>,
+    #[serde(default)]
+    pub transfer: Option<TransferMode>,
+}
+
+/// A library with all its settings resolved
+pub struct Library {
+    pub name: String,
+    pub dir_watch: Vec<WatchDir>,
     pub show_path: PathBuf,
     pub movie_path: PathBuf,
+    /// Sort everything as this type instead of guessing it from the file name
+    pub media_type: Option<MediaType>,
     pub permissions: PermissionConfig,
+    pub overwrite: bool,
+    pub transfer: TransferMode,
+}
+
+/// The configuration file.
+/// A single library can be configured with the top level dir_watch, show_path and movie_path
+/// (the "default" library), others are listed in libraries.
+#[derive(Serialize, Deserialize)]
+pub struct Config {
+    #[serde(default)]
+    pub dir_watch: Option<WatchDirConfig>,
+    #[serde(default)]
+    pub show_path: Option<PathBuf>,
+    #[serde(default)]
+    pub movie_path: Option<PathBuf>,
+    #[serde(default)]
+    pub permissions: Option<PermissionConfig>,
     pub omdb: OmdbConfig,
+    #[serde(default)]
     pub overwrite: bool,
+    #[serde(default)]
+    pub transfer: TransferMode,
+    /// Compare the checksums of the source and the copy before replacing the destination
+    #[serde(default)]
+    pub verify_copies: bool,
+    /// Polling interval in seconds of the watched folders that cannot be watched with inotify
+    #[serde(default = "Config::default_poll_interval")]
+    pub poll_interval: u64,
+    #[serde(default)]
+    pub naming: NamingConfig,
+    #[serde(default)]
+    pub settle: SettleConfig,
+    #[serde(default)]
+    pub workers: WorkersConfig,
+    /// Where the operations are recorded, so that they can be undone
+    #[serde(default = "Config::default_journal")]
+    pub journal: PathBuf,
+    #[serde(default)]
+    pub index: IndexConfig,
+    #[serde(default)]
+    pub calendar: CalendarConfig,
+    #[serde(default, rename = "libraries")]
+    pub library_configs: Vec<LibraryConfig>,
+    #[serde(skip)]
+    pub libraries: Vec<Library>,
 }
 
 impl Config {
+    fn default_poll_interval() -> u64 {
+        60
+    }
+
+    fn default_journal() -> PathBuf {
+        PathBuf::from("/var/lib/media-sort-rs/journal.jsonl")
+    }
+
     pub fn from_file(file: &Path) -> Result<Config> {
         let config_file = std::fs::File::open(file)?;
-        match serde_yaml::from_reader(config_file) {
-            Ok(c) => Ok(c),
+        let mut config: Config = match serde_yaml::from_reader(config_file) {
+            Ok(c) => c,
             Err(e) => bail!("Cannot load config file: {}", e),
+        };
+
+        if let Err(e) = config.naming.validate() {
+            bail!("Invalid naming config: {}", e);
+        }
+
+        config.libraries = config.resolve_libraries()?;
+
+        Ok(config)
+    }
+
+    fn resolve_libraries(&self) -> Result<Vec<Library>> {
+        let mut libraries = vec![];
+
+        match (&self.dir_watch, &self.show_path, &self.movie_path) {
+            (Some(dir_watch), Some(show_path), Some(movie_path)) => libraries.push(Library {
+                name: "default".to_string(),
+                dir_watch: vec![dir_watch.resolve()],
+                show_path: show_path.clone(),
+                movie_path: movie_path.clone(),
+                media_type: None,
+                permissions: match &self.permissions {
+                    Some(p) => p.clone(),
+                    None => bail!("No permissions for the default library"),
+                },
+                overwrite: self.overwrite,
+                transfer: self.transfer,
+            }),
+            (None, None, None) => {}
+            _ => {
+                bail!("dir_watch, show_path and movie_path must all be set for the default library")
+            }
+        }
+
+        for lib in &self.library_configs {
+            if libraries.iter().any(|l: &Library| l.name == lib.name) {
+                bail!("Duplicate library name: {}", lib.name);
+            }
+            if lib.dir_watch.is_empty() {
+                bail!("No dir_watch for library {}", lib.name);
+            }
+
+            libraries.push(Library {
+                name: lib.name.clone(),
+                dir_watch: lib.dir_watch.iter().map(|d| d.resolve()).collect(),
+                show_path: lib.show_path.clone(),
+                movie_path: lib.movie_path.clone(),
+                media_type: lib.media_type,
+                permissions: match (&lib.permissions, &self.permissions) {
+                    (Some(p), _) | (None, Some(p)) => p.clone(),
+                    (None, None) => bail!("No permissions for library {}", lib.name),
+                },
+                overwrite: lib.overwrite.unwrap_or(self.overwrite),
+                transfer: lib.transfer.unwrap_or(self.transfer),
+            });
         }
+
+        if libraries.is_empty() {
+            bail!("No library configured");
+        }
+
+        Ok(libraries)
+    }
+
+    /// Get the library that watches the given path: the one with the deepest watch folder
+    /// containing it.
+    pub fn library_for(&self, path: &Path) -> Option<&Library> {
+        self.libraries
+            .iter()
+            .filter_map(|l| {
+                l.dir_watch
+                    .iter()
+                    .filter(|w| path.starts_with(&w.path))
+                    .map(|w| w.path.components().count())
+                    .max()
+                    .map(|depth| (l, depth))
+            })
+            .max_by_key(|(_, depth)| *depth)
+            .map(|(l, _)| l)
+    }
+
+    pub fn library(&self, name: &str) -> Option<&Library> {
+        self.libraries.iter().find(|l| l.name == name)
+    }
+}
+
+#[cfg(test)]
+mod config_tests {
+    use crate::config::{Config, NamingConfig};
+    use crate::transfer::TransferMode;
+    use std::path::Path;
+    use std::time::Duration;
+
+    fn config(yaml: &str) -> anyhow::Result<Config> {
+        let mut config: Config = serde_yaml::from_str(&format!("omdb: {{apikey: x}}\n{yaml}"))?;
+        config.libraries = config.resolve_libraries()?;
+        Ok(config)
+    }
+
+    const PERMISSIONS: &str = "permissions: {mode: 0o644, user: media, group: media}\n";
+
+    #[test]
+    fn check_default_library() {
+        let config = config(&format!(
+            "dir_watch: /downloads\nshow_path: /shows\nmovie_path: /movies\n{PERMISSIONS}"
+        ))
+        .unwrap();
+
+        assert_eq!(config.libraries.len(), 1);
+        let library = &config.libraries[0];
+        assert_eq!(library.name, "default");
+        assert_eq!(library.dir_watch[0].path, Path::new("/downloads"));
+        assert_eq!(library.dir_watch[0].poll, None);
+        assert_eq!(library.transfer, TransferMode::Move);
+        assert!(!library.overwrite);
+        assert!(config.library_for(Path::new("/downloads/a.mkv")).is_some());
+        assert!(config.library_for(Path::new("/other/a.mkv")).is_none());
+    }
+
+    #[test]
+    fn check_libraries() {
+        let config = config(&format!(
+            "{PERMISSIONS}overwrite: true\ntransfer: copy\nlibraries:
+  - name: all
+    dir_watch: [/downloads]
+    show_path: /shows
+    movie_path: /movies
+  - name: kids
+    dir_watch: [/downloads/kids, {{path: /mnt/nas/kids, poll: 30}}]
+    show_path: /kids/shows
+    movie_path: /kids/movies
+    permissions: {{mode: 0o640, user: kids, group: kids}}
+    overwrite: false
+    transfer: hardlink
+"
+        ))
+        .unwrap();
+
+        let (all, kids) = (&config.libraries[0], &config.libraries[1]);
+        assert_eq!(all.permissions.user, "media");
+        assert!(all.overwrite);
+        assert_eq!(all.transfer, TransferMode::Copy);
+        assert_eq!(kids.permissions.mode, 0o640);
+        assert!(!kids.overwrite);
+        assert_eq!(kids.transfer, TransferMode::Hardlink);
+        assert_eq!(kids.dir_watch[1].poll, Some(Duration::from_secs(30)));
+
+        // The deepest watch folder wins
+        let library_for = |path: &str| config.library_for(Path::new(path)).map(|l| &l.name);
+        assert_eq!(library_for("/downloads/a.mkv").unwrap(), "all");
+        assert_eq!(library_for("/downloads/kids/a.mkv").unwrap(), "kids");
+        assert_eq!(library_for("/downloads/kidsshow/a.mkv").unwrap(), "all");
+        assert_eq!(library_for("/mnt/nas/kids/a.mkv").unwrap(), "kids");
+        assert!(config.library("kids").is_some());
+    }
+
+    #[test]
+    fn check_invalid_libraries() {
+        let library = |name: &str| {
+            format!("  - {{name: {name}, dir_watch: [/{name}], show_path: /s, movie_path: /m}}\n")
+        };
+
+        // Nothing configured
+        assert!(config(PERMISSIONS).is_err());
+        // Incomplete default library
+        assert!(config(&format!(
+            "dir_watch: /downloads\nshow_path: /shows\n{PERMISSIONS}"
+        ))
+        .is_err());
+        // No permissions
+        assert!(config("dir_watch: /d\nshow_path: /s\nmovie_path: /m\n").is_err());
+        assert!(config(&format!("libraries:\n{}", library("a"))).is_err());
+        // Same name twice, or as the default library
+        assert!(config(&format!(
+            "{PERMISSIONS}libraries:\n{}{}",
+            library("a"),
+            library("a")
+        ))
+        .is_err());
+        assert!(config(&format!(
+            "dir_watch: /d\nshow_path: /s\nmovie_path: /m\n{PERMISSIONS}libraries:\n{}",
+            library("default")
+        ))
+        .is_err());
+        // No watched folder
+        assert!(config(&format!(
+            "{PERMISSIONS}libraries:\n  - {{name: a, dir_watch: [], show_path: /s, movie_path: /m}}\n"
+        ))
+        .is_err());
+        assert!(config(&format!("{PERMISSIONS}libraries:\n{}", library("a"))).is_ok());
+    }
+
+    #[test]
+    fn check_episode_name() {
+        let naming = |episode: &str| -> NamingConfig {
+            serde_yaml::from_str(&format!("episode: \"{episode}\"")).unwrap()
+        };
+
+        assert!(NamingConfig::default().validate().is_ok());
+        assert!(naming("{title} - S{season}E{episode}< - {episode_title}>")
+            .validate()
+            .is_ok());
+        assert!(naming("{title} - {season}x{episode:02}")
+            .validate()
+            .is_err());
+        assert!(naming("Season {season}/{episode} - {episode_title}")
+            .validate()
+            .is_err());
     }
 }
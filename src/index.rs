@@ -0,0 +1,356 @@
+use crate::mediainfo::MediaInfo;
+use crate::showindex::ShowIndex;
+use chrono::Local;
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct IndexConfig {
+    #[serde(default = "IndexConfig::default_path")]
+    pub path: PathBuf,
+    /// Store a checksum of each file. The whole file has to be read.
+    #[serde(default)]
+    pub hash: bool,
+}
+
+impl IndexConfig {
+    fn default_path() -> PathBuf {
+        PathBuf::from("/var/lib/media-sort-rs/index.sqlite")
+    }
+}
+
+impl Default for IndexConfig {
+    fn default() -> Self {
+        IndexConfig {
+            path: Self::default_path(),
+            hash: false,
+        }
+    }
+}
+
+/// A file or disc structure in a library
+pub struct Item {
+    pub path: PathBuf,
+    pub library: String,
+    pub info: MediaInfo,
+    pub size: u64,
+    pub hash: Option<u128>,
+    /// Name of the file before it was sorted
+    pub original_name: String,
+}
+
+impl Item {
+    /// Create the item of the given path, reading its size and, if hash is true, its checksum.
+    pub fn new(
+        path: &Path,
+        library: &str,
+        info: MediaInfo,
+        original_name: &str,
+        hash: bool,
+    ) -> Item {
+        Item {
+            path: path.to_path_buf(),
+            library: library.to_string(),
+            info,
+            size: size(path),
+            hash: match hash && path.is_file() {
+                true => crate::transfer::checksum(path).ok(),
+                false => None,
+            },
+            original_name: original_name.to_string(),
+        }
+    }
+}
+
+/// Total size of a file or of a folder
+pub fn size(path: &Path) -> u64 {
+    let meta = match path.symlink_metadata() {
+        Ok(m) => m,
+        Err(_) => return 0,
+    };
+
+    match meta.is_dir() {
+        true => path
+            .read_dir()
+            .map(|entries| entries.flatten().map(|e| size(&e.path())).sum())
+            .unwrap_or(0),
+        false => meta.len(),
+    }
+}
+
+/// Key identifying the same edition of a show episode or movie, whatever its file name.
+/// The videos of a media are the same whatever their format, each kind of subtitles is apart.
+fn media_key(path: &Path, info: &MediaInfo) -> String {
+    let (title, _) = ShowIndex::normalize(&info.name);
+    let kind = MediaInfo::subtitle_extension(path).unwrap_or("video");
+    let edition = info.edition.as_deref().unwrap_or_default().to_lowercase();
+
+    let media = match &info.show_info {
+        Some(s) => format!("show/{title}/{}/{}", s.season, s.episode),
+        None => match info.year {
+            Some(y) => format!("movie/{title}/{y}"),
+            None => format!("movie/{title}"),
+        },
+    };
+    format!("{media}/{edition}/{kind}")
+}
+
+/// SQLite index of the items placed in the libraries
+pub struct Index {
+    conn: Mutex<Connection>,
+}
+
+impl Index {
+    pub fn open(path: &Path) -> anyhow::Result<Index> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+
+        let conn = Connection::open(path)?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS items (
+                path TEXT PRIMARY KEY,
+                library TEXT NOT NULL,
+                media_key TEXT NOT NULL,
+                kind TEXT NOT NULL,
+                title TEXT NOT NULL,
+                year INTEGER,
+                season INTEGER,
+                episode TEXT,
+                resolution TEXT,
+                edition TEXT,
+                imdb_id TEXT,
+                tvmaze_id INTEGER,
+                size INTEGER NOT NULL,
+                hash TEXT,
+                original_name TEXT NOT NULL,
+                added TEXT NOT NULL,
+                info TEXT NOT NULL
+            );
+            CREATE INDEX IF NOT EXISTS items_media ON items (library, media_key);",
+        )?;
+
+        Ok(Index {
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// Add or replace the item at the path of the given item
+    pub fn add(&self, item: &Item) -> anyhow::Result<()> {
+        let info = &item.info;
+        let show = info.show_info.as_ref();
+
+        self.conn.lock().unwrap().execute(
+            "INSERT OR REPLACE INTO items (path, library, media_key, kind, title, year, season,
+                episode, resolution, edition, imdb_id, tvmaze_id, size, hash, original_name, added,
+                info)
+            VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17)",
+            params![
+                item.path.to_string_lossy(),
+                item.library,
+                media_key(&item.path, info),
+                if info.is_show() { "show" } else { "movie" },
+                info.name,
+                info.year,
+                show.map(|s| s.season),
+                show.map(|s| s.episode.to_string()),
+                info.resolution,
+                info.edition,
+                info.imdb_id,
+                info.tvmaze_id.map(|i| i as i64),
+                item.size as i64,
+                item.hash.map(|h| format!("{h:032x}")),
+                item.original_name,
+                Local::now().to_rfc3339(),
+                serde_json::to_string(info)?,
+            ],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn remove(&self, path: &Path) -> anyhow::Result<()> {
+        self.conn.lock().unwrap().execute(
+            "DELETE FROM items WHERE path = ?1",
+            params![path.to_string_lossy()],
+        )?;
+
+        Ok(())
+    }
+
+    pub fn get(&self, path: &Path) -> anyhow::Result<Option<Item>> {
+        let conn = self.conn.lock().unwrap();
+        let item = conn
+            .query_row(
+                "SELECT path, library, info, size, hash, original_name FROM items
+                WHERE path = ?1",
+                params![path.to_string_lossy()],
+                Self::read_item,
+            )
+            .optional()?;
+
+        Ok(item)
+    }
+
+    /// Get the paths of the items of the library that are the same episode or movie as the file at
+    /// path matched to info, in the same edition and of the same kind
+    pub fn find(
+        &self,
+        library: &str,
+        path: &Path,
+        info: &MediaInfo,
+    ) -> anyhow::Result<Vec<PathBuf>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT path FROM items WHERE library = ?1 AND media_key = ?2 ORDER BY path",
+        )?;
+        let paths = stmt
+            .query_map(params![library, media_key(path, info)], |row| {
+                row.get::<_, String>(0)
+            })?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        Ok(paths.into_iter().map(PathBuf::from).collect())
+    }
+
+    /// Get the paths of the items of the library
+    pub fn paths(&self, library: &str) -> anyhow::Result<HashSet<PathBuf>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare("SELECT path FROM items WHERE library = ?1")?;
+        let paths = stmt
+            .query_map(params![library], |row| row.get::<_, String>(0))?
+            .collect::<rusqlite::Result<Vec<String>>>()?;
+
+        Ok(paths.into_iter().map(PathBuf::from).collect())
+    }
+
+    /// Get the episodes and movies that are more than once in a library, grouped by media.
+    pub fn duplicates(&self) -> anyhow::Result<Vec<Vec<Item>>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT path, library, info, size, hash, original_name, media_key FROM items
+            WHERE (library, media_key) IN (
+                SELECT library, media_key FROM items GROUP BY library, media_key
+                HAVING COUNT(*) > 1
+            )
+            ORDER BY library, media_key, path",
+        )?;
+
+        let mut groups: Vec<Vec<Item>> = vec![];
+        let mut last_key = None;
+        let rows = stmt.query_map([], |row| {
+            Ok((
+                (row.get::<_, String>(1)?, row.get::<_, String>(6)?),
+                Self::read_item(row)?,
+            ))
+        })?;
+
+        for row in rows {
+            let (key, item) = row?;
+            match (&last_key, groups.last_mut()) {
+                (Some(k), Some(group)) if k == &key => group.push(item),
+                _ => groups.push(vec![item]),
+            }
+            last_key = Some(key);
+        }
+
+        Ok(groups)
+    }
+
+    /// Count the items of each kind in each library
+    pub fn counts(&self) -> anyhow::Result<Vec<(String, String, u64)>> {
+        let conn = self.conn.lock().unwrap();
+        let mut stmt = conn.prepare(
+            "SELECT library, kind, COUNT(*) FROM items GROUP BY library, kind
+            ORDER BY library, kind",
+        )?;
+        let counts = stmt
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+
+        Ok(counts)
+    }
+
+    fn read_item(row: &rusqlite::Row) -> rusqlite::Result<Item> {
+        let info: String = row.get(2)?;
+        let hash: Option<String> = row.get(4)?;
+
+        Ok(Item {
+            path: PathBuf::from(row.get::<_, String>(0)?),
+            library: row.get(1)?,
+            info: serde_json::from_str(&info).map_err(|e| {
+                rusqlite::Error::FromSqlConversionFailure(2, rusqlite::types::Type::Text, e.into())
+            })?,
+            size: row.get::<_, i64>(3)? as u64,
+            hash: hash.and_then(|h| u128::from_str_radix(&h, 16).ok()),
+            original_name: row.get(5)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod index_tests {
+    use crate::index::{Index, Item};
+    use crate::mediainfo::{Episode, MediaInfo, TVShowInfo};
+    use std::path::PathBuf;
+
+    #[test]
+    fn check_duplicates() {
+        let index = Index::open(&PathBuf::from(":memory:")).unwrap();
+        let info = |name: &str, year| MediaInfo {
+            name: name.to_string(),
+            year,
+            show_info: Some(TVShowInfo {
+                season: 1,
+                episode: Episode::Numbered(2),
+                title: None,
+            }),
+            resolution: None,
+            edition: None,
+            imdb_id: None,
+            tvmaze_id: None,
+        };
+        let item = |path: &str, info| Item {
+            path: PathBuf::from(path),
+            library: "default".into(),
+            info,
+            size: 1,
+            hash: None,
+            original_name: path.into(),
+        };
+
+        index
+            .add(&item("/shows/a.mkv", info("The Show", None)))
+            .unwrap();
+        index
+            .add(&item("/shows/b.mkv", info("the show", Some(2005))))
+            .unwrap();
+        index
+            .add(&item("/shows/c.mkv", info("Other", None)))
+            .unwrap();
+
+        let duplicates = index.duplicates().unwrap();
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(
+            duplicates[0].iter().map(|i| &i.path).collect::<Vec<_>>(),
+            vec![
+                &PathBuf::from("/shows/a.mkv"),
+                &PathBuf::from("/shows/b.mkv")
+            ]
+        );
+
+        index.remove(&PathBuf::from("/shows/a.mkv")).unwrap();
+        assert!(index.duplicates().unwrap().is_empty());
+        assert_eq!(
+            index
+                .get(&PathBuf::from("/shows/b.mkv"))
+                .unwrap()
+                .unwrap()
+                .size,
+            1
+        );
+    }
+}
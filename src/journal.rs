@@ -0,0 +1,387 @@
+use crate::index::{self, Index};
+use crate::mediainfo::MediaInfo;
+use crate::transfer;
+use crate::transfer::TransferMode;
+use anyhow::bail;
+use chrono::{DateTime, Local, NaiveDate, NaiveDateTime, TimeZone};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::UNIX_EPOCH;
+
+/// The file that was at the destination and has been replaced
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Overwritten {
+    pub size: u64,
+    /// Modification time, in seconds since the epoch
+    pub modified: u64,
+}
+
+impl Overwritten {
+    pub fn from_path(path: &Path) -> Option<Overwritten> {
+        let meta = path.symlink_metadata().ok()?;
+        let modified = meta
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Some(Overwritten {
+            size: meta.len(),
+            modified,
+        })
+    }
+}
+
+/// Size and modification time of a source, to detect that it changed since it was sorted or planned
+#[derive(Serialize, Deserialize, Clone, Eq, PartialEq, Debug)]
+pub struct Fingerprint {
+    pub size: u64,
+    /// Modification time, in seconds since the epoch
+    pub modified: u64,
+}
+
+impl Fingerprint {
+    pub fn from_path(path: &Path) -> Option<Fingerprint> {
+        let modified = path
+            .symlink_metadata()
+            .ok()?
+            .modified()
+            .ok()
+            .and_then(|m| m.duration_since(UNIX_EPOCH).ok())
+            .map(|d| d.as_secs())
+            .unwrap_or(0);
+
+        Some(Fingerprint {
+            size: index::size(path),
+            modified,
+        })
+    }
+}
+
+/// An operation written in the journal
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Entry {
+    /// Unique identifier: the run and the number of the operation in the run
+    #[serde(default)]
+    pub id: String,
+    /// Identifier of the process that made the operation
+    #[serde(default)]
+    pub run: String,
+    /// RFC 3339 time of the operation
+    #[serde(default)]
+    pub time: String,
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    pub transfer: TransferMode,
+    /// Show or movie path of the library, the folders created up to it are removed by undo
+    pub root: PathBuf,
+    /// What the source was matched to
+    #[serde(default)]
+    pub info: Option<MediaInfo>,
+    #[serde(default)]
+    pub overwritten: Option<Overwritten>,
+    /// The source when it was sorted
+    #[serde(default)]
+    pub fingerprint: Option<Fingerprint>,
+    /// Id of the entry reverted by this one
+    #[serde(default)]
+    pub undoes: Option<String>,
+}
+
+impl Entry {
+    pub fn new(source: &Path, destination: &Path, transfer: TransferMode, root: &Path) -> Entry {
+        Entry {
+            id: String::new(),
+            run: String::new(),
+            time: String::new(),
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            transfer,
+            root: root.to_path_buf(),
+            info: None,
+            overwritten: None,
+            fingerprint: None,
+            undoes: None,
+        }
+    }
+
+    fn time(&self) -> Option<DateTime<Local>> {
+        DateTime::parse_from_rfc3339(&self.time)
+            .ok()
+            .map(|t| t.with_timezone(&Local))
+    }
+}
+
+/// The operations to undo
+pub enum Undo {
+    /// The last operation on a file, given by its source or its destination
+    File(PathBuf),
+    /// All the operations of a run
+    Run(String),
+    /// All the operations made since the given time
+    Since(DateTime<Local>),
+}
+
+impl Undo {
+    /// Parse a local time as "2024-01-31", "2024-01-31 18:00", "2024-01-31 18:00:00" or
+    /// RFC 3339.
+    pub fn since(time: &str) -> anyhow::Result<Undo> {
+        if let Ok(t) = DateTime::parse_from_rfc3339(time) {
+            return Ok(Undo::Since(t.with_timezone(&Local)));
+        }
+
+        let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M"]
+            .iter()
+            .find_map(|f| NaiveDateTime::parse_from_str(time, f).ok())
+            .or_else(|| {
+                NaiveDate::parse_from_str(time, "%Y-%m-%d")
+                    .ok()
+                    .and_then(|d| d.and_hms_opt(0, 0, 0))
+            });
+
+        match naive.and_then(|n| Local.from_local_datetime(&n).earliest()) {
+            Some(t) => Ok(Undo::Since(t)),
+            None => bail!("Invalid time: {time}"),
+        }
+    }
+
+    fn matches(&self, entry: &Entry) -> bool {
+        match self {
+            Undo::File(path) => &entry.source == path || &entry.destination == path,
+            Undo::Run(run) => &entry.run == run,
+            Undo::Since(since) => entry.time().map(|t| &t >= since).unwrap_or(false),
+        }
+    }
+}
+
+/// Append-only journal of the operations, one JSON entry per line.
+pub struct Journal {
+    path: PathBuf,
+    run: String,
+    count: AtomicUsize,
+    file: Mutex<Option<File>>,
+}
+
+impl Journal {
+    pub fn new(path: &Path) -> Journal {
+        Journal {
+            path: path.to_path_buf(),
+            run: format!(
+                "{}-{}",
+                Local::now().format("%Y%m%d-%H%M%S"),
+                std::process::id()
+            ),
+            count: AtomicUsize::new(0),
+            file: Mutex::new(None),
+        }
+    }
+
+    pub fn run(&self) -> &str {
+        &self.run
+    }
+
+    /// Append the given operation to the journal. Its id, run and time are set here.
+    pub fn record(&self, mut entry: Entry) -> anyhow::Result<()> {
+        let count = self.count.fetch_add(1, Ordering::SeqCst);
+        entry.id = format!("{}.{count}", self.run);
+        entry.run = self.run.clone();
+        entry.time = Local::now().to_rfc3339();
+
+        let mut line = serde_json::to_string(&entry)?;
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        if file.is_none() {
+            if let Some(parent) = self.path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            *file = Some(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(&self.path)?,
+            );
+        }
+
+        // A single write per entry, so that entries of concurrent runs are not mixed
+        let file = file.as_mut().unwrap();
+        file.write_all(line.as_bytes())?;
+        file.sync_data()?;
+
+        Ok(())
+    }
+
+    /// Read all the entries. Lines that cannot be parsed are reported and skipped.
+    pub fn entries(&self) -> anyhow::Result<Vec<Entry>> {
+        let file = match File::open(&self.path) {
+            Ok(f) => f,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(e) => bail!("Cannot open the journal {:?}: {e}", self.path),
+        };
+
+        let mut entries = vec![];
+        for (i, line) in BufReader::new(file).lines().enumerate() {
+            let line = line?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            match serde_json::from_str(&line) {
+                Ok(e) => entries.push(e),
+                Err(e) => eprintln!("Invalid journal entry at line {}: {e}. Ignoring...", i + 1),
+            }
+        }
+
+        Ok(entries)
+    }
+
+    /// Get the sources of the operations that were not undone, with their fingerprint when they
+    /// were sorted
+    pub fn sorted(&self) -> anyhow::Result<HashMap<PathBuf, Fingerprint>> {
+        let entries = self.entries()?;
+        let undone: HashSet<&str> = entries.iter().filter_map(|e| e.undoes.as_deref()).collect();
+
+        Ok(entries
+            .iter()
+            .filter(|e| e.undoes.is_none() && !undone.contains(e.id.as_str()))
+            .filter_map(|e| Some((e.source.clone(), e.fingerprint.clone()?)))
+            .collect())
+    }
+
+    /// Revert the selected operations, the most recent first. The reverted items are removed from
+    /// the index.
+    /// If dry_run is true, the actions will be logged but not executed.
+    pub fn undo(&self, undo: &Undo, index: Option<&Index>, dry_run: bool) -> anyhow::Result<()> {
+        let entries = self.entries()?;
+        let undone: HashSet<&str> = entries.iter().filter_map(|e| e.undoes.as_deref()).collect();
+
+        let mut selected: Vec<&Entry> = entries
+            .iter()
+            .filter(|e| e.undoes.is_none() && !undone.contains(e.id.as_str()))
+            .filter(|e| undo.matches(e))
+            .collect();
+
+        if let Undo::File(_) = undo {
+            selected = selected.pop().into_iter().collect();
+        }
+
+        if selected.is_empty() {
+            println!("Nothing to undo");
+            return Ok(());
+        }
+
+        let mut failed = 0;
+        for entry in selected.iter().rev() {
+            match self.revert(entry, index, dry_run) {
+                Ok(_) => {}
+                Err(e) => {
+                    eprintln!("Cannot undo {:?}: {e}", entry.destination);
+                    failed += 1;
+                }
+            }
+        }
+
+        if failed > 0 {
+            bail!("{failed} operations could not be undone");
+        }
+
+        Ok(())
+    }
+
+    fn revert(&self, entry: &Entry, index: Option<&Index>, dry_run: bool) -> anyhow::Result<()> {
+        let (src, dst) = (&entry.source, &entry.destination);
+
+        if dst.symlink_metadata().is_err() {
+            bail!("{dst:?} does not exist anymore");
+        }
+
+        // Linked and copied files are only removed from the library if their source is still
+        // there, otherwise they are moved back.
+        let remove = entry.transfer != TransferMode::Move && src.exists();
+        if !remove && src.symlink_metadata().is_ok() {
+            bail!("{src:?} already exists");
+        }
+
+        match remove {
+            true => println!("Remove {dst:?}, {src:?} is still there"),
+            false => println!("Move {dst:?} back to {src:?}"),
+        }
+        if entry.overwritten.is_some() {
+            eprintln!("Warning: {dst:?} had replaced an existing file, that cannot be restored");
+        }
+
+        if dry_run {
+            return Ok(());
+        }
+
+        if !remove {
+            transfer::transfer(dst, src, TransferMode::Move, false, false)?;
+        } else if dst.is_dir() && !dst.is_symlink() {
+            fs::remove_dir_all(dst)?;
+        } else {
+            fs::remove_file(dst)?;
+        }
+
+        if let Some(index) = index {
+            index.remove(dst)?;
+        }
+
+        // Remove the show, season or movie folders left empty
+        let mut parent = dst.parent();
+        while let Some(p) = parent {
+            if p == entry.root || !p.starts_with(&entry.root) || fs::remove_dir(p).is_err() {
+                break;
+            }
+            parent = p.parent();
+        }
+
+        let mut undo = Entry::new(dst, src, entry.transfer, &entry.root);
+        undo.undoes = Some(entry.id.clone());
+        self.record(undo)
+    }
+}
+
+#[cfg(test)]
+mod journal_tests {
+    use crate::journal::{Entry, Fingerprint, Journal, Undo};
+    use crate::transfer::TransferMode;
+    use std::fs;
+
+    #[test]
+    fn check_undo() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (src, root) = (dir.join("watch/Movie.2001.mkv"), dir.join("movies"));
+        let dst = root.join("Movie (2001)/Movie (2001).mkv");
+        fs::create_dir_all(dst.parent().unwrap()).unwrap();
+        fs::create_dir_all(src.parent().unwrap()).unwrap();
+        fs::write(&dst, "movie").unwrap();
+
+        let journal = Journal::new(&dir.join("journal.jsonl"));
+        let mut entry = Entry::new(&src, &dst, TransferMode::Move, &root);
+        entry.fingerprint = Fingerprint::from_path(&dst);
+        journal.record(entry).unwrap();
+        assert_eq!(
+            journal.sorted().unwrap().get(&src),
+            Fingerprint::from_path(&dst).as_ref()
+        );
+
+        journal.undo(&Undo::File(dst.clone()), None, false).unwrap();
+        assert_eq!(fs::read_to_string(&src).unwrap(), "movie");
+        assert!(!dst.parent().unwrap().exists());
+        assert!(journal.sorted().unwrap().is_empty());
+        assert!(root.exists());
+
+        // Already undone
+        journal
+            .undo(&Undo::Run(journal.run().into()), None, false)
+            .unwrap();
+        assert!(src.exists());
+        assert_eq!(journal.entries().unwrap().len(), 2);
+    }
+}
@@ -1,11 +1,27 @@
+mod calendar;
 mod config;
+mod index;
+mod journal;
 mod mediainfo;
 mod mediasort;
+mod naming;
 mod omdb;
+mod plan;
+mod pool;
+mod queue;
+mod report;
+mod sanitize;
+mod settle;
+mod showindex;
+mod transfer;
 mod tvmaze;
+mod watcher;
 
 use anyhow::bail;
+use index::Index;
+use journal::{Journal, Undo};
 use std::path::PathBuf;
+use std::process::ExitCode;
 use structopt::StructOpt;
 
 #[derive(StructOpt)]
@@ -25,27 +41,132 @@ struct Options {
     /// Check the give folder for files that would be sorted in the same files.
     /// This is useful to be sure that a better version of a show will not be overwritten by a less
     /// good one.
-    /// This will show a summary of which files are conflicting and which file could not be sorted
-    /// because it was not found online
+    /// This will show a summary of which files are conflicting, which files are already in the
+    /// library with their quality compared to the existing ones, and which file could not be
+    /// sorted because it was not found online
     /// Nothing will be moved (--dry-run has no effect)
     #[structopt(long)]
     check: Option<PathBuf>,
+
+    /// With --sort, write the planned operations to the given file instead of sorting. The plan
+    /// can be reviewed and edited, e.g. to fix a match, before it is applied with --apply.
+    #[structopt(long, requires = "sort")]
+    plan: Option<PathBuf>,
+
+    /// Carry out the operations of the given plan file. Files that changed since the plan are
+    /// refused.
+    #[structopt(long)]
+    apply: Option<PathBuf>,
+
+    /// Library used by --sort and --check.
+    /// Defaults to the library watching the given folder, or the first configured library.
+    #[structopt(long)]
+    library: Option<String>,
+
+    /// Undo the last sort of the given file, given by its source or destination path.
+    /// The operations are read from the configured journal.
+    #[structopt(long)]
+    undo_file: Option<PathBuf>,
+
+    /// Undo all the operations of the given run, as printed when the run starts
+    #[structopt(long)]
+    undo_run: Option<String>,
+
+    /// Undo all the operations made since the given local time: "2024-01-31",
+    /// "2024-01-31 18:00" or RFC 3339
+    #[structopt(long)]
+    undo_since: Option<String>,
+
+    /// Import the files already in the libraries, or in the --library one, into the index
+    #[structopt(long)]
+    scan: bool,
+
+    /// List the episodes and movies that are more than once in a library, according to the index
+    #[structopt(long)]
+    duplicates: bool,
+
+    /// Report the aired episodes that are missing from the show folders of the libraries, or of
+    /// the --library one, according to TVMaze
+    #[structopt(long)]
+    missing: bool,
+
+    /// Write the upcoming episodes of the shows of the libraries, or of the --library one, to
+    /// the given iCalendar file
+    #[structopt(long)]
+    calendar: Option<PathBuf>,
+
+    /// Output format of the reports: table or json, or ndjson or csv with one record per file for
+    /// --sort and --check.
+    /// --sort exits with code 2 if some media files were not sorted. --check exits with code 2 if
+    /// some media files cannot be matched, or else 3 if some files conflict or are already in the
+    /// library.
+    #[structopt(long, default_value = "table")]
+    output: report::Output,
 }
 
-fn main() -> anyhow::Result<()> {
-    let opts = Options::from_args();
+#[tokio::main]
+async fn main() -> anyhow::Result<ExitCode> {
+    let mut opts = Options::from_args();
+
+    // The paths are recorded in the journal and matched with the watched folders: they must not
+    // depend on the current directory
+    for path in [&mut opts.sort, &mut opts.check, &mut opts.undo_file]
+        .into_iter()
+        .flatten()
+    {
+        *path = std::path::absolute(&path)?;
+    }
     let config = match config::Config::from_file(&opts.config) {
         Ok(c) => c,
         Err(e) => bail!("Cannot open {:?}: {e}", &opts.config),
     };
 
+    let undo = if let Some(path) = &opts.undo_file {
+        Some(Undo::File(path.clone()))
+    } else if let Some(run) = &opts.undo_run {
+        Some(Undo::Run(run.clone()))
+    } else if let Some(time) = &opts.undo_since {
+        Some(Undo::since(time)?)
+    } else {
+        None
+    };
+
+    if let Some(undo) = undo {
+        let index = match Index::open(&config.index.path) {
+            Ok(i) => Some(i),
+            Err(e) => {
+                eprintln!("Cannot open the index {:?}: {e}", config.index.path);
+                None
+            }
+        };
+        Journal::new(&config.journal).undo(&undo, index.as_ref(), opts.dry_run)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
     let mut sorter = mediasort::MediaSort::new(config, opts.dry_run)?;
 
-    if opts.sort.is_some() {
-        sorter.sort(&opts.sort.unwrap(), opts.dry_run)
-    } else if opts.check.is_some() {
-        sorter.check(&opts.check.unwrap())
+    if opts.scan {
+        sorter.scan(opts.library.as_deref())?
+    } else if opts.duplicates {
+        sorter.duplicates()?
+    } else if opts.missing {
+        sorter.missing(opts.library.as_deref(), opts.output).await?
+    } else if let Some(path) = &opts.calendar {
+        sorter.calendar(path, opts.library.as_deref()).await?
+    } else if let Some(path) = &opts.sort {
+        let library = sorter.library(path, opts.library.as_deref())?;
+        match &opts.plan {
+            Some(plan) => sorter.plan(path, library, plan).await?,
+            None => return sorter.sort(path, library, opts.dry_run, opts.output).await,
+        }
+    } else if let Some(plan) = &opts.apply {
+        return sorter.apply(plan, opts.dry_run, opts.output).await;
+    } else if let Some(path) = &opts.check {
+        let library = sorter.library(path, opts.library.as_deref())?;
+        return sorter.check(path, library, opts.output).await;
     } else {
-        sorter.watch()
+        sorter.watch().await?
     }
+
+    Ok(ExitCode::SUCCESS)
 }
@@ -4,9 +4,26 @@ use crate::omdb::OMDB;
 use crate::tvmaze::TVMaze;
 use anyhow::bail;
 use chrono::{Datelike, Utc};
-use std::path::PathBuf;
+use reqwest::Client;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
 
-#[derive(Eq, PartialEq, Debug)]
+/// Folders that mark the root of a DVD or Blu-ray disc structure
+const DISC_FOLDERS: [&str; 2] = ["VIDEO_TS", "BDMV"];
+
+/// Extensions of the files that are sorted
+const MEDIA_EXTENSIONS: [&str; 7] = ["mkv", "avi", "mp4", "srt", "idx", "sub", "iso"];
+const SUBTITLE_EXTENSIONS: [&str; 3] = ["srt", "idx", "sub"];
+
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum MediaType {
+    Show,
+    Movie,
+}
+
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
+#[serde(rename_all = "lowercase")]
 pub enum Episode {
     Numbered(u8),
     Special(String),
@@ -14,13 +31,13 @@ pub enum Episode {
 
 impl Episode {
     pub fn new(e: u8, title: &str) -> Episode {
-        return if e != 0 {
+        if e != 0 {
             Episode::Numbered(e)
         } else if title.is_empty() {
             Episode::Special("Unknown Special".to_string())
         } else {
             Episode::Special(Self::capitalize_words(title))
-        };
+        }
     }
 
     fn capitalize_words(value: &str) -> String {
@@ -53,17 +70,23 @@ impl Display for Episode {
     }
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
 pub struct TVShowInfo {
     pub season: u8,
     pub episode: Episode,
+    /// Episode title, as found online
+    pub title: Option<String>,
 }
 
-#[derive(Eq, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, Eq, PartialEq, Debug, Clone)]
 pub struct MediaInfo {
     pub name: String,
     pub year: Option<i32>,
     pub show_info: Option<TVShowInfo>,
+    pub resolution: Option<String>,
+    pub edition: Option<String>,
+    pub imdb_id: Option<String>,
+    pub tvmaze_id: Option<u64>,
 }
 
 impl MediaInfo {
@@ -71,41 +94,181 @@ impl MediaInfo {
         self.show_info.is_some()
     }
 
-    pub fn from_path(path: &PathBuf, omdb_apikey: &str) -> anyhow::Result<MediaInfo> {
-        match path.extension() {
-            None => bail!("No extension: {}", path.to_str().unwrap_or("")),
-            Some(ext) => match ext.to_str() {
-                Some("mkv") | Some("avi") | Some("mp4") | Some("srt") | Some("idx")
-                | Some("sub") => {}
-                _ => bail!("Unknown extension: {}", path.to_str().unwrap_or("")),
-            },
+    /// Check if the given directory is the root of a DVD or Blu-ray structure.
+    pub fn is_disc(path: &Path) -> bool {
+        path.is_dir() && DISC_FOLDERS.iter().any(|d| path.join(d).is_dir())
+    }
+
+    /// Check if the given path is a VIDEO_TS or BDMV folder
+    pub fn is_disc_folder(path: &Path) -> bool {
+        path.is_dir()
+            && path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .map(|n| DISC_FOLDERS.contains(&n))
+                .unwrap_or(false)
+    }
+
+    /// Get the disc structure the given path belongs to, if any, among the folders inside root.
+    /// It is the folder that contains the VIDEO_TS or BDMV folder, or the VIDEO_TS or BDMV folder
+    /// itself when it is directly in root: root is never a disc structure.
+    pub fn disc_root(path: &Path, root: &Path) -> Option<PathBuf> {
+        path.ancestors()
+            .take_while(|p| *p != root && p.starts_with(root))
+            .find(|p| Self::is_disc(p) || (Self::is_disc_folder(p) && p.parent() == Some(root)))
+            .map(|p| p.to_path_buf())
+    }
+
+    /// Check if the given path is a file with a sorted extension or a disc structure
+    pub fn is_media(path: &Path) -> bool {
+        Self::is_disc(path)
+            || path
+                .extension()
+                .and_then(|e| e.to_str())
+                .map(|e| MEDIA_EXTENSIONS.contains(&e))
+                .unwrap_or(false)
+    }
+
+    /// Get the extension of the given path if it is a subtitle file
+    pub fn subtitle_extension(path: &Path) -> Option<&str> {
+        path.extension()
+            .and_then(|e| e.to_str())
+            .filter(|e| SUBTITLE_EXTENSIONS.contains(e))
+    }
+
+    /// Get the media info of a file or disc structure already in a library, without online
+    /// lookup. The title and year come from the show folder, or from the movie folder or file,
+    /// named "Title (Year)". Provider ids are read from tags such as "[imdbid-tt0111161]" or
+    /// "[tvmazeid-123]".
+    pub fn from_library_path(path: &Path, root: &Path, media_type: MediaType) -> Option<MediaInfo> {
+        let relative = path.strip_prefix(root).ok()?;
+        let mut components = relative.components();
+        let top = components.next()?.as_os_str().to_string_lossy().to_string();
+        let in_folder = components.next().is_some();
+
+        let mut info = Self::extract_media_info(path);
+        let name = match media_type {
+            MediaType::Show => {
+                info.show_info.as_ref()?;
+                top
+            }
+            MediaType::Movie => {
+                info.show_info = None;
+                match in_folder || Self::is_disc(path) {
+                    true => top,
+                    false => path.file_stem()?.to_string_lossy().to_string(),
+                }
+            }
+        };
+
+        (info.name, info.year) = Self::library_title(&name);
+        (info.imdb_id, info.tvmaze_id) = Self::library_ids(path);
+
+        Some(info)
+    }
+
+    /// Get the title and year of a library folder or file named "Title (Year) [tags]"
+    pub fn library_title(name: &str) -> (String, Option<i32>) {
+        let tags = regex::Regex::new(r"\[[^\]]*\]|\{[^}]*\}").unwrap();
+        let title_re = regex::Regex::new(r"^(?P<title>.*?)\s*\((?P<year>\d{4})\)").unwrap();
+
+        let name = tags.replace_all(name, "").trim().to_string();
+        match title_re.captures(&name) {
+            Some(c) => (c["title"].to_string(), c["year"].parse().ok()),
+            None => (name, None),
         }
+    }
 
-        let media_info = Self::extract_media_info(&path);
+    /// Get the IMDb and TVMaze ids found in the tags of a library path
+    pub fn library_ids(path: &Path) -> (Option<String>, Option<u64>) {
+        let path = path.to_string_lossy();
+        let imdb = regex::Regex::new(r"\b(tt\d{7,})\b").unwrap();
+        let tvmaze = regex::Regex::new(r"tvmaze(?:id)?[-=](\d+)").unwrap();
+
+        (
+            imdb.captures(&path).map(|c| c[1].to_string()),
+            tvmaze.captures(&path).and_then(|c| c[1].parse().ok()),
+        )
+    }
+
+    /// Get the media info of the given file or disc structure.
+    /// If media_type is set, the media is looked up as that type whatever its name looks like.
+    /// The title of an episode is only looked up if episode_title is true.
+    pub async fn from_path(
+        path: &Path,
+        client: &Client,
+        omdb_apikey: &str,
+        media_type: Option<MediaType>,
+        episode_title: bool,
+    ) -> anyhow::Result<MediaInfo> {
+        let mut media_info = if Self::is_disc(path) {
+            // The title of a disc structure comes from its enclosing folder and it is always a
+            // movie.
+            let mut info =
+                Self::extract_name_info(&path.file_name().unwrap_or_default().to_string_lossy());
+            info.show_info = None;
+            info
+        } else {
+            match path.extension() {
+                None => bail!("No extension: {}", path.to_str().unwrap_or("")),
+                Some(_) if Self::is_media(path) => {}
+                Some(_) => bail!("Unknown extension: {}", path.to_str().unwrap_or("")),
+            }
+
+            Self::extract_media_info(path)
+        };
+
+        match media_type {
+            Some(MediaType::Movie) => media_info.show_info = None,
+            Some(MediaType::Show) if !media_info.is_show() => {
+                bail!(
+                    "No season and episode found: {}",
+                    path.to_str().unwrap_or("")
+                )
+            }
+            _ => {}
+        }
 
         Ok(match media_info.show_info {
-            Some(i) => match TVMaze::search_show(&media_info.name, media_info.year) {
-                Some(res) => MediaInfo {
-                    name: res.show.name,
-                    year: media_info.year,
-                    show_info: Some(TVShowInfo {
-                        season: i.season,
-                        episode: i.episode,
-                    }),
-                },
-                None => bail!(
-                    "Show not found: {} ({})",
-                    &media_info.name,
-                    media_info.year.unwrap_or(-1)
-                ),
-            },
+            Some(i) => {
+                let tvmaze = TVMaze::new(client);
+                match tvmaze.search_show(&media_info.name, media_info.year).await {
+                    Some(res) => MediaInfo {
+                        show_info: Some(TVShowInfo {
+                            title: match i.episode {
+                                Episode::Numbered(e) if episode_title => {
+                                    tvmaze.episode_title(res.show.id, i.season, e).await
+                                }
+                                _ => None,
+                            },
+                            season: i.season,
+                            episode: i.episode,
+                        }),
+                        name: res.show.name,
+                        year: media_info.year,
+                        resolution: media_info.resolution,
+                        edition: media_info.edition,
+                        imdb_id: res.show.externals.imdb,
+                        tvmaze_id: Some(res.show.id),
+                    },
+                    None => bail!(
+                        "Show not found: {} ({})",
+                        &media_info.name,
+                        media_info.year.unwrap_or(-1)
+                    ),
+                }
+            }
             None => {
-                let omdb = OMDB::new(omdb_apikey);
-                match omdb.search_movie(&media_info.name, media_info.year) {
+                let omdb = OMDB::new(omdb_apikey, client);
+                match omdb.search_movie(&media_info.name, media_info.year).await {
                     Some(res) => MediaInfo {
                         name: res.title,
                         year: media_info.year,
                         show_info: None,
+                        resolution: media_info.resolution,
+                        edition: media_info.edition,
+                        imdb_id: res.imdb_id,
+                        tvmaze_id: None,
                     },
                     None => bail!(
                         "Movie not found: {} ({})",
@@ -117,40 +280,40 @@ impl MediaInfo {
         })
     }
 
-    fn extract_media_info(path: &PathBuf) -> MediaInfo {
+    pub fn extract_media_info(path: &Path) -> MediaInfo {
+        Self::extract_name_info(&path.file_stem().unwrap_or_default().to_string_lossy())
+    }
+
+    /// Extract the media info from a file name without extension or from a folder name.
+    fn extract_name_info(name: &str) -> MediaInfo {
         let mut media_info = MediaInfo {
-            name: Self::path_normalize(path),
+            name: Self::name_normalize(name),
             year: None,
             show_info: None,
+            resolution: None,
+            edition: None,
+            imdb_id: None,
+            tvmaze_id: None,
         };
 
         media_info.extract_show_season_episode();
 
         media_info.extract_year();
 
+        media_info.extract_release_info(&name.to_ascii_lowercase());
+
         media_info
     }
 
-    fn path_normalize(path: &PathBuf) -> String {
+    fn name_normalize(name: &str) -> String {
         let punctuation = regex::Regex::new(r"[\.\-_]").unwrap();
         let encodings = regex::Regex::new(
             r"(720p|1080p|1440p|2160p|hdtv|x264|dts|bluray|aac|atmos|x265|hevc|h264|h265|web|webrip|imax|multi|extended).*",
         )
             .unwrap();
         let parenthesis = regex::Regex::new(r"\(.*\)").unwrap();
-        let mut path = path.clone();
 
-        path.set_extension("");
-
-        let mut name = if let Some(file_name) = path.file_name() {
-            if let Some(lower) = file_name.to_ascii_lowercase().to_str() {
-                lower.to_string()
-            } else {
-                "".to_string()
-            }
-        } else {
-            "".to_string()
-        };
+        let mut name = name.to_ascii_lowercase();
 
         name = punctuation.replace_all(&name, " ").to_string();
         name = encodings.replace(&name, "").to_string();
@@ -167,22 +330,47 @@ impl MediaInfo {
          */
         let year_re = regex::Regex::new(r"^(?P<title>.*) (?P<year>\d{4})$").unwrap();
 
-        match year_re.captures(&self.name) {
-            None => {}
-            Some(c) => match c["year"].parse::<i32>() {
-                Ok(y) => {
-                    let now = Utc::now();
-                    // We consider that the first movie made was "The Horse in Motion" in 1878
-                    if (1878..=now.year()).contains(&y) {
-                        self.name = c["title"].to_string();
-                        self.year = Some(y);
-                    }
+        if let Some(c) = year_re.captures(&self.name) {
+            if let Ok(y) = c["year"].parse::<i32>() {
+                let now = Utc::now();
+                // We consider that the first movie made was "The Horse in Motion" in 1878
+                if (1878..=now.year()).contains(&y) {
+                    self.name = c["title"].to_string();
+                    self.year = Some(y);
                 }
-                Err(_) => {}
-            },
+            }
         };
     }
 
+    /// Extract the information about the release that the name normalization throws away.
+    fn extract_release_info(&mut self, name: &str) {
+        let resolution = regex::Regex::new(
+            r"(?:^|[^a-z0-9])(?P<res>480p|576p|720p|1080p|1440p|2160p|4k)(?:$|[^a-z0-9])",
+        )
+        .unwrap();
+        let editions = [
+            ("extended", "Extended"),
+            ("director.?s.cut", "Director's Cut"),
+            ("unrated", "Unrated"),
+            ("uncut", "Uncut"),
+            ("remastered", "Remastered"),
+            ("theatrical", "Theatrical"),
+            ("imax", "IMAX"),
+            ("criterion", "Criterion"),
+        ];
+
+        self.resolution = resolution.captures(name).map(|c| match &c["res"] {
+            "4k" => "2160p".to_string(),
+            r => r.to_string(),
+        });
+
+        self.edition = editions.iter().find_map(|(pattern, edition)| {
+            let re =
+                regex::Regex::new(&format!(r"(?:^|[^a-z0-9]){pattern}(?:$|[^a-z0-9])")).unwrap();
+            re.is_match(name).then(|| edition.to_string())
+        });
+    }
+
     fn extract_show_season_episode(&mut self) {
         let se = regex::Regex::new(
             r"(?P<name>.*)[Ss](?P<season>\d{1,2})[Ee](?P<episode>\d{1,2}) *(?P<title>.*)",
@@ -205,7 +393,11 @@ impl MediaInfo {
         };
 
         self.name = caps["name"].trim().to_string();
-        self.show_info = Some(TVShowInfo { season, episode });
+        self.show_info = Some(TVShowInfo {
+            season,
+            episode,
+            title: None,
+        });
     }
 }
 
@@ -225,6 +417,10 @@ mod mediainfo_tests {
                 name: String::from("test title 22"),
                 year: None,
                 show_info: None,
+                resolution: None,
+                edition: None,
+                imdb_id: None,
+                tvmaze_id: None,
             }
         );
 
@@ -236,6 +432,10 @@ mod mediainfo_tests {
                 name: String::from("test title"),
                 year: Some(1922),
                 show_info: None,
+                resolution: None,
+                edition: None,
+                imdb_id: None,
+                tvmaze_id: None,
             }
         );
 
@@ -248,6 +448,10 @@ mod mediainfo_tests {
                 name: String::from("test 2022 title 42"),
                 year: None,
                 show_info: None,
+                resolution: None,
+                edition: None,
+                imdb_id: None,
+                tvmaze_id: None,
             }
         );
 
@@ -262,7 +466,12 @@ mod mediainfo_tests {
                 show_info: Some(TVShowInfo {
                     season: 13,
                     episode: Special(String::from("Special Title")),
+                    title: None,
                 }),
+                resolution: Some(String::from("1080p")),
+                edition: None,
+                imdb_id: None,
+                tvmaze_id: None,
             }
         );
 
@@ -275,7 +484,12 @@ mod mediainfo_tests {
                 show_info: Some(TVShowInfo {
                     season: 13,
                     episode: Special(String::from("Unknown Special")),
+                    title: None,
                 }),
+                resolution: Some(String::from("1080p")),
+                edition: None,
+                imdb_id: None,
+                tvmaze_id: None,
             }
         );
 
@@ -290,8 +504,47 @@ mod mediainfo_tests {
                 show_info: Some(TVShowInfo {
                     season: 13,
                     episode: Episode::Numbered(3),
+                    title: None,
                 }),
+                resolution: Some(String::from("1080p")),
+                edition: None,
+                imdb_id: None,
+                tvmaze_id: None,
+            }
+        );
+    }
+
+    #[test]
+    fn check_disc_folder_name() {
+        assert_eq!(
+            MediaInfo::extract_name_info("Great.Movie.2010.Extended.1080p.BluRay.x264-GRP"),
+            MediaInfo {
+                name: String::from("great movie"),
+                year: Some(2010),
+                show_info: None,
+                resolution: Some(String::from("1080p")),
+                edition: Some(String::from("Extended")),
+                imdb_id: None,
+                tvmaze_id: None,
             }
         );
     }
+
+    #[test]
+    fn check_disc_root() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path();
+        let movie = root.join("Movie (2001)");
+        std::fs::create_dir_all(movie.join("VIDEO_TS")).unwrap();
+        std::fs::create_dir_all(root.join("BDMV")).unwrap();
+
+        let vob = movie.join("VIDEO_TS/VTS_01_1.VOB");
+        assert_eq!(MediaInfo::disc_root(&vob, root), Some(movie.clone()));
+        assert_eq!(
+            MediaInfo::disc_root(&root.join("BDMV/index.bdmv"), root),
+            Some(root.join("BDMV"))
+        );
+        // The root contains a disc structure but it is never one
+        assert_eq!(MediaInfo::disc_root(&root.join("Other.mkv"), root), None);
+    }
 }
@@ -1,214 +1,1081 @@
+use crate::calendar;
 use crate::config;
-use crate::mediainfo::MediaInfo;
+use crate::config::Library;
+use crate::index::{Index, Item};
+use crate::journal::{Entry, Fingerprint, Journal, Overwritten};
+use crate::mediainfo::{MediaInfo, MediaType};
+use crate::naming::Field;
+use crate::plan::{Plan, Step};
+use crate::pool::Limits;
+use crate::queue::EventQueue;
+use crate::report;
+use crate::report::{Action, CheckReport, Output, Record, ShowEpisodes, ShowReport};
+use crate::settle::SettleQueue;
+use crate::showindex::ShowIndexes;
+use crate::transfer;
+use crate::tvmaze::TVMaze;
+use crate::watcher::Watchers;
 use anyhow::bail;
-use libc;
-use libc::c_char;
-use notify::event::AccessKind;
-use notify::event::ModifyKind::Name;
-use notify::{
-    Config, Event, EventKind, INotifyWatcher, RecommendedWatcher, RecursiveMode, Result, Watcher,
-};
-use std::collections::HashMap;
-use std::fs;
-use std::os::unix::fs::PermissionsExt;
+use chrono::{Local, Utc};
+use notify::event::{AccessKind, AccessMode, CreateKind, Flag, ModifyKind, RenameMode};
+use notify::{Event, EventKind, Result};
+use std::collections::{HashMap, HashSet};
 use std::path::{Path, PathBuf};
-use std::sync::mpsc::Receiver;
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio::task::JoinHandle;
 
-pub(crate) struct MediaSort {
-    rx: Receiver<Result<Event>>,
+/// How often the queued files are checked
+const SETTLE_TICK: Duration = Duration::from_secs(1);
+
+/// What the tasks processing the files share
+struct Shared {
     config: config::Config,
+    /// Shared by all the TVMaze and OMDB lookups
+    client: reqwest::Client,
+    limits: Limits,
+    journal: Journal,
+    /// Not available if it cannot be opened
+    index: Option<Index>,
+    /// Overdue episodes already reported, by show id, season and episode
+    alerted: Mutex<HashSet<(u64, u16, u32)>>,
+    /// Existing show folders of the libraries
+    shows: ShowIndexes,
+}
+
+pub(crate) struct MediaSort {
+    rx: UnboundedReceiver<Result<Event>>,
+    shared: Arc<Shared>,
     dry_run: bool,
-    checked: HashMap<PathBuf, Vec<PathBuf>>,
-    _watcher: INotifyWatcher,
+    events: EventQueue,
+    settle: SettleQueue,
+    watchers: Watchers,
+    /// Items being processed by the tasks
+    in_flight: HashSet<PathBuf>,
+    done_tx: UnboundedSender<PathBuf>,
+    done_rx: UnboundedReceiver<PathBuf>,
 }
 
 impl MediaSort {
     pub fn new(config: config::Config, dry_run: bool) -> anyhow::Result<MediaSort> {
-        let (tx, rx) = std::sync::mpsc::channel();
-        let mut watcher = RecommendedWatcher::new(tx, Config::default())?;
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        let (done_tx, done_rx) = tokio::sync::mpsc::unbounded_channel();
 
-        watcher.watch(Path::new(&config.dir_watch), RecursiveMode::Recursive)?;
+        let index = match Index::open(&config.index.path) {
+            Ok(i) => Some(i),
+            Err(e) => {
+                eprintln!(
+                    "Cannot open the index {:?}: {e}. Items will not be indexed",
+                    config.index.path
+                );
+                None
+            }
+        };
 
         Ok(MediaSort {
             rx,
-            config,
+            events: EventQueue::new(),
+            settle: SettleQueue::new(config.settle.clone()),
+            shared: Arc::new(Shared {
+                client: reqwest::Client::new(),
+                limits: Limits::new(&config.workers),
+                journal: Journal::new(&config.journal),
+                index,
+                config,
+                alerted: Mutex::new(HashSet::new()),
+                shows: ShowIndexes::default(),
+            }),
             dry_run,
-            checked: HashMap::new(),
-            _watcher: watcher,
+            watchers: Watchers::new(tx),
+            in_flight: HashSet::new(),
+            done_tx,
+            done_rx,
         })
     }
 
-    pub fn watch(&mut self) -> anyhow::Result<()> {
-        for res in &self.rx {
-            match res {
-                Ok(e) => Self::process_event(&e, &self.config, self.dry_run),
-                Err(e) => anyhow::bail!("watch error: {:?}", e),
+    pub async fn watch(&mut self) -> anyhow::Result<()> {
+        // Missing folders are only reported here, they are watched once they appear
+        self.watchers.establish(&self.shared.config)?;
+
+        for library in &self.shared.config.libraries {
+            // Remove what was left by an interrupted copy
+            transfer::cleanup_partials(&library.show_path, self.dry_run);
+            transfer::cleanup_partials(&library.movie_path, self.dry_run);
+        }
+
+        self.print_run(self.dry_run);
+        self.reconcile();
+
+        let mut tick = tokio::time::interval(SETTLE_TICK);
+        let calendar_enabled = self.shared.config.calendar.is_enabled();
+        let mut calendar_tick = tokio::time::interval(Duration::from_secs(
+            self.shared.config.calendar.interval.max(1) * 3600,
+        ));
+
+        loop {
+            tokio::select! {
+                event = self.rx.recv() => match event {
+                    Some(Ok(e)) => self.process_event(&e),
+                    Some(Err(e)) => {
+                        eprintln!("Watch error: {e:?}. Re-establishing the watches...");
+                        self.watchers.lost();
+                    }
+                    None => break,
+                },
+                Some(item) = self.done_rx.recv() => {
+                    // Drop the last events of the processed item
+                    self.in_flight.remove(&item);
+                    self.events.handled(&item);
+                }
+                _ = tick.tick() => {
+                    // Events may have been missed while the watches were lost
+                    self.watchers.check();
+                    if self.watchers.recover(&self.shared.config) {
+                        self.reconcile();
+                    }
+
+                    for path in self.events.ready() {
+                        self.settle.add(&path);
+                    }
+
+                    for path in self.settle.ready() {
+                        self.process_settled(&path);
+                    }
+                }
+                _ = calendar_tick.tick(), if calendar_enabled => {
+                    tokio::spawn(Self::refresh_calendar(self.shared.clone()));
+                }
             }
         }
 
         Ok(())
     }
 
-    pub fn sort(&self, path: &PathBuf, dry_run: bool) -> anyhow::Result<()> {
+    /// Get the library used to sort the given path: the one with the given name, or else the one
+    /// watching the path, or else the first one.
+    pub fn library(&self, path: &Path, name: Option<&str>) -> anyhow::Result<&Library> {
+        match name {
+            Some(n) => match self.shared.config.library(n) {
+                Some(l) => Ok(l),
+                None => bail!("Unknown library: {n}"),
+            },
+            None => Ok(self
+                .shared
+                .config
+                .library_for(path)
+                .unwrap_or(&self.shared.config.libraries[0])),
+        }
+    }
+
+    /// Get the media items to sort in the given folder: its files and its DVD or Blu-ray
+    /// structures, that are sorted as a whole. A VIDEO_TS or BDMV folder outside of a movie folder
+    /// is an item of its own, that cannot be sorted.
+    fn media_items(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        if MediaInfo::is_disc(path) || MediaInfo::is_disc_folder(path) {
+            return Ok(vec![path.to_path_buf()]);
+        }
+
+        Self::folder_items(path)
+    }
+
+    /// Get the media items inside the given watched, sorted or library folder. The folder itself
+    /// is never an item, even if it contains a disc structure.
+    fn folder_items(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        let mut items = vec![];
         for entry in path.read_dir()? {
             let entry = entry?;
             if entry.path().is_dir() {
-                self.sort(&entry.path(), dry_run)?;
+                items.extend(Self::media_items(&entry.path())?);
             } else {
-                match Self::process_file(&entry.path(), &self.config, dry_run) {
-                    Ok(p) => println!("Sorted {entry:?} to {p:?}"),
-                    Err(e) => println!("Cannot sort {entry:?}: {e}"),
+                items.push(entry.path());
+            }
+        }
+
+        items.sort();
+        Ok(items)
+    }
+
+    /// Get the media items of a folder given to sort, check or plan
+    fn sorted_items(path: &Path) -> anyhow::Result<Vec<PathBuf>> {
+        if MediaInfo::is_disc(path) {
+            bail!("{path:?} is a disc structure: give its parent folder instead");
+        }
+
+        Self::folder_items(path)
+    }
+
+    fn print_run(&self, dry_run: bool) {
+        if !dry_run {
+            eprintln!(
+                "Run {}: operations are recorded in {:?}",
+                self.shared.journal.run(),
+                self.shared.config.journal
+            );
+        }
+    }
+
+    /// Spawn a task processing the given item with the given library.
+    fn spawn_process(&self, item: PathBuf, library: &Library, dry_run: bool) -> JoinHandle<Record> {
+        let shared = self.shared.clone();
+        let name = library.name.clone();
+
+        tokio::spawn(async move {
+            let library = shared
+                .config
+                .library(&name)
+                .expect("library from the configuration");
+            Self::process_file(&item, shared.clone(), library, dry_run).await
+        })
+    }
+
+    /// Process the given items concurrently and wait for them. The results are given in the order
+    /// of the items.
+    async fn process_items(
+        &self,
+        items: Vec<PathBuf>,
+        library: &Library,
+        dry_run: bool,
+    ) -> Vec<Record> {
+        let tasks: Vec<_> = items
+            .into_iter()
+            .map(|item| (item.clone(), self.spawn_process(item, library, dry_run)))
+            .collect();
+
+        let mut records = vec![];
+        for (item, task) in tasks {
+            let record = match task.await {
+                Ok(record) => record,
+                Err(e) => {
+                    let mut record = Record::new(&item, library.transfer);
+                    record.fail(e.into());
+                    record
+                }
+            };
+            records.push(record);
+        }
+
+        records
+    }
+
+    /// Sort the given folder. Returns the exit code: EXIT_PARTIAL if some media files were not
+    /// sorted.
+    pub async fn sort(
+        &self,
+        path: &Path,
+        library: &Library,
+        dry_run: bool,
+        output: Output,
+    ) -> anyhow::Result<ExitCode> {
+        let items = Self::sorted_items(path)?;
+        self.print_run(dry_run);
+        let records = self.process_items(items, library, dry_run).await;
+
+        report::print_sorted(&records, output)?;
+        Ok(report::sort_exit_code(&records))
+    }
+
+    /// Plan the sort of the given folder, without moving anything, and write the plan to the
+    /// given file
+    pub async fn plan(&self, path: &Path, library: &Library, file: &Path) -> anyhow::Result<()> {
+        let items = Self::sorted_items(path)?;
+        let records = self.process_items(items, library, true).await;
+
+        let plan = Plan::new(&library.name, library.transfer, records);
+        plan.write(file)?;
+
+        let planned = plan
+            .steps
+            .iter()
+            .filter(|s| matches!(s.action, Action::Sort | Action::Replace))
+            .count();
+        println!(
+            "{planned} of {} files planned in {file:?}",
+            plan.steps.len()
+        );
+        Ok(())
+    }
+
+    /// Carry out the steps of the given plan file. Steps whose source changed since the plan are
+    /// refused. The planned destinations are used, unless the match was edited: the destination
+    /// is then computed again from it.
+    /// Returns the exit code: EXIT_PARTIAL if some planned files were not sorted.
+    pub async fn apply(
+        &self,
+        file: &Path,
+        dry_run: bool,
+        output: Output,
+    ) -> anyhow::Result<ExitCode> {
+        let plan = Plan::read(file)?;
+        if self.shared.config.library(&plan.library).is_none() {
+            bail!("Unknown library {} in the plan", plan.library);
+        }
+        self.print_run(dry_run);
+
+        let tasks: Vec<_> = plan
+            .steps
+            .into_iter()
+            .map(|step| {
+                let (shared, name) = (self.shared.clone(), plan.library.clone());
+                let source = step.source.clone();
+
+                let task = tokio::spawn(async move {
+                    let library = shared
+                        .config
+                        .library(&name)
+                        .expect("library from the configuration");
+                    let mut record = Record::new(&step.source, plan.transfer);
+                    if let Err(e) =
+                        Self::apply_step(step, shared.clone(), library, dry_run, &mut record).await
+                    {
+                        record.fail(e);
+                    }
+                    record
+                });
+                (source, task)
+            })
+            .collect();
+
+        let mut records = vec![];
+        for (source, task) in tasks {
+            records.push(match task.await {
+                Ok(record) => record,
+                Err(e) => {
+                    let mut record = Record::new(&source, plan.transfer);
+                    record.fail(e.into());
+                    record
+                }
+            });
+        }
+
+        report::print_sorted(&records, output)?;
+        Ok(report::sort_exit_code(&records))
+    }
+
+    async fn apply_step(
+        step: Step,
+        shared: Arc<Shared>,
+        library: &Library,
+        dry_run: bool,
+        record: &mut Record,
+    ) -> anyhow::Result<()> {
+        if !matches!(step.action, Action::Sort | Action::Replace) {
+            record.action = Action::Skip;
+            bail!("Not planned: {}", step.error.unwrap_or_default());
+        }
+
+        step.check_source()?;
+        let edited = step.is_edited();
+        let info = match step.matched {
+            Some(i) => i,
+            None => bail!("{:?} is not matched", step.source),
+        };
+
+        let (root, dst) = match &step.destination {
+            Some(dst) if !edited => {
+                let root = [&library.show_path, &library.movie_path]
+                    .into_iter()
+                    .find(|r| dst.starts_with(r));
+                match root {
+                    Some(root) => (root.clone(), dst.clone()),
+                    None => bail!("{dst:?} is not in the library {}", library.name),
                 }
             }
+            _ => {
+                let (root, dst) = Self::destination(&step.source, &info, &shared, library)?;
+                if edited {
+                    eprintln!(
+                        "The match of {:?} was edited: sorting to {dst:?}",
+                        step.source
+                    );
+                }
+                (root, dst)
+            }
+        };
+        record.matched = Some(info);
+        record.destination = Some(dst);
+
+        Self::place(shared.clone(), library, &root, dry_run, record).await
+    }
+
+    /// Check what sorting the given folder would do, without moving anything. The conflicts,
+    /// the files already in the library, the unmatched files and the skipped extensions are
+    /// reported at the end. Returns the exit code of the report.
+    pub async fn check(
+        &self,
+        path: &Path,
+        library: &Library,
+        output: Output,
+    ) -> anyhow::Result<ExitCode> {
+        let items = Self::sorted_items(path)?;
+        let mut records = self.process_items(items, library, true).await;
+        let report = CheckReport::new(&records, self.shared.index.as_ref());
+
+        if output != Output::Table {
+            report.mark_conflicts(&mut records);
+            report::print_records(&records, output)?;
+        } else {
+            report.print();
+        }
+
+        Ok(report.exit_code())
+    }
+
+    /// Get the library with the given name, or all the libraries
+    fn libraries(&self, name: Option<&str>) -> anyhow::Result<Vec<&Library>> {
+        Ok(match name {
+            Some(n) => vec![self.library(Path::new(""), Some(n))?],
+            None => self.shared.config.libraries.iter().collect(),
+        })
+    }
+
+    /// Report the aired episodes missing from the show folders of the libraries, or of the given
+    /// library. The shows are checked concurrently.
+    pub async fn missing(&self, name: Option<&str>, output: Output) -> anyhow::Result<()> {
+        let today = Local::now().date_naive();
+        let mut folders = vec![];
+        for library in self.libraries(name)? {
+            folders.extend(report::show_folders(&library.show_path));
         }
 
+        let reports: Vec<ShowReport> = Self::fetch_shows(self.shared.clone(), folders)
+            .await?
+            .into_iter()
+            .map(|s| report::missing_episodes(s, today))
+            .collect();
+
+        report::print_missing(&reports, output)
+    }
+
+    /// Write the upcoming episodes of the shows of the libraries, or of the given library, to an
+    /// iCalendar file
+    pub async fn calendar(&self, path: &Path, name: Option<&str>) -> anyhow::Result<()> {
+        let mut folders = vec![];
+        for library in self.libraries(name)? {
+            folders.extend(report::show_folders(&library.show_path));
+        }
+
+        let shows = Self::fetch_shows(self.shared.clone(), folders).await?;
+        for show in shows.iter().filter(|s| s.error.is_some()) {
+            eprintln!(
+                "{}: {}",
+                show.show,
+                show.error.as_deref().unwrap_or_default()
+            );
+        }
+
+        std::fs::write(path, calendar::ics(&shows, Utc::now()))?;
+        println!("Calendar written to {path:?}");
         Ok(())
     }
 
-    fn do_check(&mut self, path: &PathBuf) -> anyhow::Result<()> {
-        for entry in path.read_dir()? {
-            let entry = entry?;
-            if entry.path().is_dir() {
-                self.do_check(&entry.path())?;
-            } else {
-                match Self::process_file(&entry.path(), &self.config, true) {
-                    Ok(p) => {
-                        let paths = self.checked.entry(p).or_insert(vec![]);
-                        paths.push(path.clone());
+    /// Import the files already in the libraries, or in the given library, into the index.
+    /// Files are identified by their names only, without online lookup. Indexed files that are not
+    /// in the libraries anymore are removed from the index.
+    pub fn scan(&self, name: Option<&str>) -> anyhow::Result<()> {
+        let config = &self.shared.config;
+        let index = match &self.shared.index {
+            Some(i) => i,
+            None => bail!("The index is not available"),
+        };
+        for library in self.libraries(name)? {
+            let mut found = HashSet::new();
+            let roots = [
+                (&library.show_path, MediaType::Show),
+                (&library.movie_path, MediaType::Movie),
+            ];
+
+            for (root, media_type) in roots {
+                eprintln!("Scanning {root:?}");
+                let items = match Self::folder_items(root) {
+                    Ok(items) => items,
+                    Err(e) => {
+                        eprintln!("Cannot scan {root:?}: {e}");
+                        continue;
                     }
-                    Err(e) => println!("Cannot check {entry:?}: {e}"),
+                };
+
+                for item in items {
+                    // Shows and movies may share the same folder
+                    if !MediaInfo::is_media(&item) || found.contains(&item) {
+                        continue;
+                    }
+
+                    let info = match MediaInfo::from_library_path(&item, root, media_type) {
+                        Some(i) => i,
+                        None => {
+                            eprintln!("Cannot parse {item:?}. Ignoring...");
+                            continue;
+                        }
+                    };
+
+                    // Keep the name of the file before it was sorted
+                    let original_name = match index.get(&item)? {
+                        Some(i) => i.original_name,
+                        None => item
+                            .file_name()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .to_string(),
+                    };
+
+                    let item_info = Item::new(
+                        &item,
+                        &library.name,
+                        info,
+                        &original_name,
+                        config.index.hash,
+                    );
+                    index.add(&item_info)?;
+                    found.insert(item);
                 }
             }
+
+            for path in index.paths(&library.name)?.difference(&found) {
+                eprintln!("{path:?} is not in the library anymore");
+                index.remove(path)?;
+            }
+        }
+
+        for (library, kind, count) in index.counts()? {
+            println!("{library}: {count} {kind} files");
         }
 
         Ok(())
     }
 
-    pub fn check(&mut self, path: &PathBuf) -> anyhow::Result<()> {
-        let res = self.do_check(path);
-        for (k, v) in &self.checked {
-            if v.len() > 1 {
-                println!("{k:?} -> {v:?}");
+    /// List the episodes and movies that are more than once in a library, according to the
+    /// index.
+    pub fn duplicates(&self) -> anyhow::Result<()> {
+        let index = match &self.shared.index {
+            Some(i) => i,
+            None => bail!("The index is not available"),
+        };
+
+        for group in index.duplicates()? {
+            let info = &group[0].info;
+            match &info.show_info {
+                Some(s) => println!(
+                    "{}: {} S{:02}E{}",
+                    group[0].library, info.name, s.season, s.episode
+                ),
+                None => println!(
+                    "{}: {} ({})",
+                    group[0].library,
+                    info.name,
+                    info.year.unwrap_or(-1)
+                ),
+            }
+
+            for item in &group {
+                println!(
+                    "    {:?} ({} bytes, {})",
+                    item.path,
+                    item.size,
+                    item.info
+                        .resolution
+                        .as_deref()
+                        .unwrap_or("unknown resolution")
+                );
             }
         }
 
-        res
+        Ok(())
     }
 
     /// Process the given new_file
-    /// If dry_run is true, the action will be logged but not executed.
-    /// Returns an error or the destination path once processed
-    fn process_file(
-        new_file: &PathBuf,
-        config: &config::Config,
+    /// new_file can also be the root folder of a DVD or Blu-ray structure, that is moved as a
+    /// whole.
+    /// If dry_run is true, the action will be logged but not executed, otherwise it is recorded in
+    /// the journal and the index.
+    /// The lookups and transfers are bounded by the shared limits, and the destination is locked
+    /// while it is checked and written.
+    /// Returns what was done with the file
+    async fn process_file(
+        new_file: &Path,
+        shared: Arc<Shared>,
+        library: &Library,
+        dry_run: bool,
+    ) -> Record {
+        let mut record = Record::new(new_file, library.transfer);
+        if let Err(e) = Self::sort_file(new_file, shared, library, dry_run, &mut record).await {
+            record.fail(e);
+        }
+
+        record
+    }
+
+    /// Sort the given file, filling the match, the destination and the action of its record
+    async fn sort_file(
+        new_file: &Path,
+        shared: Arc<Shared>,
+        library: &Library,
         dry_run: bool,
-    ) -> anyhow::Result<PathBuf> {
-        let info = MediaInfo::from_path(&new_file, &config.omdb.apikey)?;
+        record: &mut Record,
+    ) -> anyhow::Result<()> {
+        let (config, limits, client) = (&shared.config, &shared.limits, &shared.client);
+        if MediaInfo::is_disc_folder(new_file) {
+            bail!(
+                "{new_file:?} is not in a movie folder: move it to a folder named after the movie"
+            );
+        }
 
-        let mut dst = if info.is_show() {
-            let show = info.show_info.unwrap();
-            let mut show_path = config.show_path.join(info.name.clone());
+        let info = {
+            let _permit = limits.lookups.acquire().await?;
+            // The episode title is an extra request, only made if the name uses it
+            let episode_title = config
+                .naming
+                .episode
+                .fields()
+                .contains(&Field::EpisodeTitle);
+            MediaInfo::from_path(
+                new_file,
+                client,
+                &config.omdb.apikey,
+                library.media_type,
+                episode_title,
+            )
+            .await?
+        };
+        let (root, dst) = Self::destination(new_file, &info, &shared, library)?;
+        record.matched = Some(info);
+        record.destination = Some(dst);
 
-            // Check if the path exists without a year
-            show_path = if show_path.exists() || info.year.is_none() {
-                show_path
-            } else {
-                config
+        Self::place(shared.clone(), library, &root, dry_run, record).await
+    }
+
+    /// Get the library root and the destination of the given file matched to info
+    fn destination(
+        new_file: &Path,
+        info: &MediaInfo,
+        shared: &Shared,
+        library: &Library,
+    ) -> anyhow::Result<(PathBuf, PathBuf)> {
+        let config = &shared.config;
+        let (root, mut dst) = if info.is_show() {
+            let naming = &config.naming;
+
+            // Reuse the existing folder of the show, if any
+            let show_path = match shared.shows.find(&library.show_path, &info.name, info.year) {
+                Some(p) => p,
+                None => library
                     .show_path
-                    .join(format!("{} ({})", info.name.clone(), info.year.unwrap()))
+                    .join(naming.show_folder.render(info, naming.sanitize)?),
             };
 
-            show_path
-                .join(format!("Season {:02}", show.season))
-                .join(format!(
-                    "{} - S{:02}E{}",
-                    info.name.clone(),
-                    show.season,
-                    show.episode
-                ))
+            (
+                &library.show_path,
+                show_path.join(naming.episode.render(info, naming.sanitize)?),
+            )
         } else {
-            config.movie_path.join(match info.year {
-                Some(y) => format!("{} ({})", info.name.clone(), y),
-                None => info.name.clone(),
-            })
+            let naming = &config.naming;
+            let movie_path = match &naming.movie_folder {
+                // A disc structure is the content of the movie folder
+                Some(folder) if new_file.is_dir() => library
+                    .movie_path
+                    .join(folder.render(info, naming.sanitize)?),
+                Some(folder) => library
+                    .movie_path
+                    .join(folder.render(info, naming.sanitize)?)
+                    .join(naming.movie.render(info, naming.sanitize)?),
+                None => library
+                    .movie_path
+                    .join(naming.movie.render(info, naming.sanitize)?),
+            };
+
+            (&library.movie_path, movie_path)
         };
 
-        if let Some(e) = new_file.extension() {
-            dst.set_extension(e);
+        // Disc structures are moved to a folder named after the movie.
+        // The extension is appended as titles may contain dots.
+        if !new_file.is_dir() {
+            if let Some(e) = new_file.extension() {
+                let mut name = dst.file_name().unwrap_or_default().to_os_string();
+                name.push(".");
+                name.push(e);
+                dst.set_file_name(name);
+            }
         }
 
-        if dst.exists() && !config.overwrite {
-            bail!("{dst:?} already exists: Skipping")
+        Ok((root.to_path_buf(), dst))
+    }
+
+    /// Put the source of the record at its destination, in the given library root, with its
+    /// transfer mode.
+    /// If dry_run is true, the action will be logged but not executed.
+    async fn place(
+        shared: Arc<Shared>,
+        library: &Library,
+        root: &Path,
+        dry_run: bool,
+        record: &mut Record,
+    ) -> anyhow::Result<()> {
+        let (config, limits) = (&shared.config, &shared.limits);
+        let (new_file, mode) = (record.source.clone(), record.transfer);
+        let (info, dst) = match (&record.matched, &record.destination) {
+            (Some(i), Some(d)) => (i.clone(), d.clone()),
+            _ => bail!("{new_file:?} is not matched"),
+        };
+        let _lock = limits.destinations.lock(&dst).await;
+
+        // The index knows the same episode or movie under another name
+        if let Some(index) = &shared.index {
+            if let Ok(paths) = index.find(&library.name, &dst, &info) {
+                if let Some(other) = paths.into_iter().find(|p| *p != dst && p.exists()) {
+                    record.action = Action::Conflict;
+                    record.duplicate = Some(other.clone());
+                    bail!("{new_file:?} is already in the library as {other:?}");
+                }
+            }
+        }
+
+        if dst.exists() {
+            if !library.overwrite {
+                record.action = Action::Skip;
+                bail!("{dst:?} already exists: Skipping")
+            }
+            record.action = Action::Replace;
         }
 
-        println!("move {:?} to {:?}", new_file, dst);
+        eprintln!("{} {:?} to {:?}", mode, new_file, dst);
 
         if dry_run {
-            return Ok(dst);
+            return Ok(());
         }
 
-        if let Some(parent) = dst.parent() {
-            fs::create_dir_all(parent)?;
+        let _permit = limits.transfers.acquire().await?;
+
+        // The file system calls block: they run on the blocking threads of the runtime
+        let (src, root, permissions) = (
+            new_file.to_path_buf(),
+            root.to_path_buf(),
+            library.permissions.clone(),
+        );
+        let (verify, overwrite) = (config.verify_copies, library.overwrite);
+        let mut entry = Entry::new(&src, &dst, mode, &root);
+        entry.info = Some(info.clone());
+        let (task_shared, name) = (shared.clone(), library.name.clone());
+
+        let res = tokio::task::spawn_blocking(move || -> anyhow::Result<Entry> {
+            let shared = task_shared;
+            entry.overwritten = Overwritten::from_path(&dst);
+            entry.fingerprint = Fingerprint::from_path(&src);
+            transfer::transfer(&src, &dst, mode, verify, overwrite)?;
+            transfer::apply_permissions(&dst, &root, &permissions, mode)?;
+
+            if let Some(index) = &shared.index {
+                let original_name = src.file_name().unwrap_or_default().to_string_lossy();
+                let item = Item::new(&dst, &name, info, &original_name, shared.config.index.hash);
+                if let Err(e) = index.add(&item) {
+                    eprintln!("Cannot add {dst:?} to the index: {e}");
+                }
+            }
+
+            Ok(entry)
+        })
+        .await?;
+
+        let entry = match res {
+            Ok(e) => e,
+            // dst appeared while the file was transferred
+            Err(e) if transfer::is_conflict(&e) => {
+                record.action = Action::Skip;
+                return Err(e);
+            }
+            Err(e) => return Err(e),
+        };
+
+        let dst = entry.destination.clone();
+        if let Err(e) = shared.journal.record(entry) {
+            eprintln!("Cannot record {dst:?} in the journal: {e}");
         }
-        match fs::rename(&new_file, &dst) {
-            Err(_) => {
-                // Maybe new_file and dst are on different FS, try to copy the file instead.
-                fs::copy(&new_file, &dst)?;
-                fs::remove_file(&new_file)?;
+
+        Ok(())
+    }
+
+    /// Queue everything already in the watched folders, e.g. files that arrived while the service
+    /// was stopped. They go through the same checks as the files announced by events.
+    /// The sources left by copies and links that are unchanged since they were sorted are skipped.
+    fn reconcile(&mut self) {
+        let sorted = match self.shared.journal.sorted() {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Cannot read the journal: {e}");
+                HashMap::new()
+            }
+        };
+
+        let dirs: Vec<PathBuf> = self
+            .shared
+            .config
+            .libraries
+            .iter()
+            .flat_map(|l| l.dir_watch.iter().map(|d| d.path.clone()))
+            .collect();
+
+        for dir in dirs {
+            eprintln!("Scanning {dir:?}");
+            match Self::folder_items(&dir) {
+                Ok(items) => {
+                    for item in items {
+                        let unchanged = matches!(
+                            sorted.get(&item),
+                            Some(f) if Some(f) == Fingerprint::from_path(&item).as_ref()
+                        );
+                        if !unchanged && self.is_sortable(&item) {
+                            self.events.touch(&item);
+                        }
+                    }
+                }
+                Err(e) => eprintln!("Cannot scan {dir:?}: {e}"),
             }
-            _ => {}
         }
+    }
 
-        let ret = dst.clone();
+    /// Queue the files that were written or moved in the watched folders. They are processed
+    /// once their events stopped and their content is complete.
+    fn process_event(&mut self, e: &Event) {
+        // Events were lost, e.g. the inotify queue overflowed: the folders are scanned again
+        if e.flag() == Some(Flag::Rescan) {
+            eprintln!("Events were lost. Scanning the watched folders again...");
+            self.reconcile();
+            return;
+        }
 
-        // Set the permissions
-        while dst != config.show_path && dst != config.movie_path {
-            let mut perms = fs::metadata(&dst)?.permissions();
-            let mode = config.permissions.mode + if dst.is_dir() { 0o111 } else { 0 };
-            perms.set_mode(mode);
-            fs::set_permissions(&dst, perms)?;
+        match (e.kind, e.paths.as_slice()) {
+            (EventKind::Access(AccessKind::Close(AccessMode::Write)), [path])
+                if self.is_sortable(path) =>
+            {
+                eprintln!("closed: {:?}", path);
+                self.events.touch(path);
+            }
+            (EventKind::Modify(ModifyKind::Name(RenameMode::To)), [path])
+                if self.is_sortable(path) =>
+            {
+                eprintln!("Renamed: {:?}", path);
+                self.events.touch(path);
+            }
+            (EventKind::Modify(ModifyKind::Name(RenameMode::Both)), [from, to]) => {
+                if self.is_sortable(to) {
+                    self.events.rename(from, to);
+                } else {
+                    self.events.forget(from);
+                }
+            }
+            (EventKind::Modify(ModifyKind::Name(RenameMode::From)), [path])
+            | (EventKind::Remove(_), [path]) => self.events.forget(path),
+            // Polled folders only report new paths, the settle stage waits for them to be
+            // complete.
+            (EventKind::Create(CreateKind::Any), [path]) if self.is_sortable(path) => {
+                eprintln!("Found: {:?}", path);
+                self.events.touch(path);
+            }
+            // Files copied in a new folder before it is watched do not raise events: sort the
+            // whole folder once it is complete.
+            (EventKind::Create(CreateKind::Folder), [path]) if self.is_sortable(path) => {
+                eprintln!("Created: {:?}", path);
+                self.events.touch(path);
+            }
+            (EventKind::Modify(ModifyKind::Data(_)), [path]) => self.events.refresh(path),
+            _ => {}
+        }
+    }
 
-            // Set user/group
-            unsafe {
-                let pwd = libc::getpwnam(config.permissions.user.as_ptr() as *const c_char);
+    /// Check if the given path from an event has to be sorted.
+    fn is_sortable(&self, path: &Path) -> bool {
+        let library = match self.shared.config.library_for(path) {
+            Some(l) => l,
+            None => return false,
+        };
+        let watched = library
+            .dir_watch
+            .iter()
+            .filter(|w| path.starts_with(&w.path))
+            .max_by_key(|w| w.path.components().count());
 
-                if !pwd.is_null() {
-                    let pwd = *pwd;
+        // Files of a disc structure are sorted with the whole structure, not one by one.
+        !matches!(watched.and_then(|w| MediaInfo::disc_root(path, &w.path)), Some(root) if root != path)
+    }
 
-                    libc::chown(
-                        dst.to_str().unwrap().as_ptr() as *const c_char,
-                        pwd.pw_uid,
-                        pwd.pw_gid,
-                    );
+    /// Sort a complete file, or all the files of a folder that arrived in a watched folder.
+    fn process_settled(&mut self, path: &Path) {
+        let library = match self.shared.config.library_for(path) {
+            Some(l) => l,
+            None => return,
+        };
+
+        let items = if path.is_dir() {
+            match Self::media_items(path) {
+                Ok(items) => items,
+                Err(err) => {
+                    eprintln!("Cannot read {:?}: {err}. Ignoring...", path);
+                    return;
                 }
             }
+        } else {
+            vec![path.to_path_buf()]
+        };
+
+        for item in items {
+            if self.events.is_handled(&item) || self.in_flight.contains(&item) {
+                continue;
+            }
+
+            self.in_flight.insert(item.clone());
+            self.events.handled(&item);
 
-            dst.pop();
+            let task = self.spawn_process(item.clone(), library, self.dry_run);
+            let done = self.done_tx.clone();
+
+            tokio::spawn(async move {
+                match task.await {
+                    Ok(Record { error: None, .. }) => {}
+                    Ok(Record {
+                        error: Some(err), ..
+                    }) => {
+                        eprintln!("Cannot process {:?}: {err}. Ignoring...", item);
+                        // TODO: There should be a way to notify the issue
+                        //       A nice way would be via Home assistant
+                    }
+                    Err(err) => eprintln!("Cannot process {:?}: {err}. Ignoring...", item),
+                }
+                let _ = done.send(item);
+            });
+        }
+    }
+
+    /// Get the episodes on TVMaze of the shows in the given folders, concurrently
+    async fn fetch_shows(
+        shared: Arc<Shared>,
+        folders: Vec<PathBuf>,
+    ) -> anyhow::Result<Vec<ShowEpisodes>> {
+        let tasks: Vec<JoinHandle<ShowEpisodes>> = folders
+            .into_iter()
+            .map(|folder| {
+                let shared = shared.clone();
+                tokio::spawn(async move {
+                    let _permit = shared.limits.lookups.acquire().await;
+                    let tvmaze = TVMaze::new(&shared.client);
+                    report::show_episodes(&folder, &tvmaze).await
+                })
+            })
+            .collect();
+
+        let mut shows = vec![];
+        for task in tasks {
+            shows.push(task.await?);
         }
 
-        Ok(ret)
+        Ok(shows)
     }
 
-    fn process_event(e: &Event, config: &config::Config, dry_run: bool) {
-        // FIXME: Sometimes, it looks like folders are just created...
-        match e.kind {
-            EventKind::Access(AccessKind::Close(_)) => {
-                println!("closed: {:?}", e.paths[0]);
-                if let Err(err) = Self::process_file(&e.paths[0], config, dry_run) {
-                    println!("Cannot process {:?}: {err}. Ignoring...", e.paths[0]);
-                    // TODO: There should be a way to notify the issue
-                    //       A nice way would be via Home assistant
-                }
+    /// Rewrite the configured calendar and report the episodes that are overdue for the first time
+    async fn refresh_calendar(shared: Arc<Shared>) {
+        let config = &shared.config.calendar;
+        let folders = shared
+            .config
+            .libraries
+            .iter()
+            .flat_map(|l| report::show_folders(&l.show_path))
+            .collect();
+
+        let shows = match Self::fetch_shows(shared.clone(), folders).await {
+            Ok(s) => s,
+            Err(e) => {
+                eprintln!("Cannot refresh the calendar: {e}");
+                return;
             }
-            EventKind::Modify(Name(notify::event::RenameMode::To)) => {
-                println!("Renamed: {:?}", e.paths[0]);
-                if let Err(err) = Self::process_file(&e.paths[0], config, dry_run) {
-                    println!("Cannot process {:?}: {err}. Ignoring...", e.paths[0]);
-                    // TODO: There should be a way to notify the issue
-                    //       A nice way would be via Home assistant
+        };
+
+        if let Some(path) = &config.path {
+            if let Err(e) = std::fs::write(path, calendar::ics(&shows, Utc::now())) {
+                eprintln!("Cannot write the calendar {path:?}: {e}");
+            }
+        }
+
+        let days = match config.overdue_days {
+            Some(d) => d,
+            None => return,
+        };
+        let today = Local::now().date_naive();
+        let mut alerted = shared.alerted.lock().unwrap();
+
+        for show in &shows {
+            for episode in calendar::overdue(show, today, days) {
+                let key = (
+                    show.tvmaze_id.unwrap_or_default(),
+                    episode.season,
+                    episode.number.unwrap_or_default(),
+                );
+                if alerted.insert(key) {
+                    eprintln!(
+                        "Overdue: {} - S{:02}E{:02} - {} aired on {} and has not arrived",
+                        show.show,
+                        key.1,
+                        key.2,
+                        episode.name,
+                        episode.airdate.as_deref().unwrap_or_default()
+                    );
                 }
             }
-            _ => {}
         }
     }
 }
+
+#[cfg(test)]
+mod mediasort_tests {
+    use crate::config::Config;
+    use crate::mediainfo::MediaInfo;
+    use crate::mediasort::MediaSort;
+    use crate::report::{Action, Record};
+    use crate::transfer::TransferMode;
+    use std::fs;
+
+    #[tokio::test]
+    async fn check_subtitles_conflicts() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let config = format!(
+            "dir_watch: {0}/watch\nshow_path: {0}/shows\nmovie_path: {0}/movies\n\
+            permissions: {{mode: 0o644, user: root, group: root}}\nomdb: {{apikey: x}}\n\
+            journal: {0}/journal.jsonl\nindex: {{path: {0}/index.sqlite}}\n",
+            dir.display()
+        );
+        fs::write(dir.join("config.yaml"), config).unwrap();
+        let sorter =
+            MediaSort::new(Config::from_file(&dir.join("config.yaml")).unwrap(), false).unwrap();
+        let library = &sorter.shared.config.libraries[0];
+        fs::create_dir_all(dir.join("watch")).unwrap();
+
+        let sort = async |name: &str| {
+            let source = dir.join("watch").join(name);
+            fs::write(&source, name).unwrap();
+            let info = MediaInfo::extract_media_info(&source);
+            let (root, dst) =
+                MediaSort::destination(&source, &info, &sorter.shared, library).unwrap();
+
+            let mut record = Record::new(&source, TransferMode::Move);
+            record.matched = Some(info);
+            record.destination = Some(dst);
+            let res = MediaSort::place(sorter.shared.clone(), library, &root, false, &mut record);
+            let _ = res.await;
+            record.action
+        };
+
+        assert_eq!(sort("The.Show.S01E02.mkv").await, Action::Sort);
+        assert_eq!(sort("The.Show.S01E02.srt").await, Action::Sort);
+        assert_eq!(sort("The.Show.S01E02.idx").await, Action::Sort);
+        assert_eq!(sort("The.Show.S01E02.sub").await, Action::Sort);
+        // The same episode in another format
+        assert_eq!(sort("The.Show.S01E02.mp4").await, Action::Conflict);
+        assert!(sorter
+            .shared
+            .index
+            .as_ref()
+            .unwrap()
+            .duplicates()
+            .unwrap()
+            .is_empty());
+    }
+}
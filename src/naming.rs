@@ -0,0 +1,354 @@
+use crate::mediainfo::MediaInfo;
+use crate::sanitize::Sanitize;
+use anyhow::bail;
+use serde::{Deserialize, Serialize};
+use std::fmt::{Display, Formatter};
+use std::path::PathBuf;
+
+/// Values that can be used in a naming template, as `{name}` or `{name:0N}` to pad numbers
+/// with zeros.
+/// A part of a template enclosed in `<` and `>` is optional: it is left out when one of the values
+/// it uses is unknown, e.g. `{title}< ({year})>`.
+/// Literal braces are written `{{` and `}}`.
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Field {
+    Title,
+    Year,
+    Season,
+    Episode,
+    EpisodeTitle,
+    Resolution,
+    Edition,
+    ImdbId,
+    TvmazeId,
+}
+
+impl Field {
+    const ALL: [Field; 9] = [
+        Field::Title,
+        Field::Year,
+        Field::Season,
+        Field::Episode,
+        Field::EpisodeTitle,
+        Field::Resolution,
+        Field::Edition,
+        Field::ImdbId,
+        Field::TvmazeId,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Field::Title => "title",
+            Field::Year => "year",
+            Field::Season => "season",
+            Field::Episode => "episode",
+            Field::EpisodeTitle => "episode_title",
+            Field::Resolution => "resolution",
+            Field::Edition => "edition",
+            Field::ImdbId => "imdb_id",
+            Field::TvmazeId => "tvmaze_id",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<Field> {
+        Self::ALL.iter().find(|f| f.name() == name).copied()
+    }
+
+    fn value(&self, info: &MediaInfo) -> Option<String> {
+        let show = info.show_info.as_ref();
+        match self {
+            Field::Title => Some(info.name.clone()),
+            Field::Year => info.year.map(|y| y.to_string()),
+            Field::Season => show.map(|s| s.season.to_string()),
+            Field::Episode => show.map(|s| s.episode.to_string()),
+            Field::EpisodeTitle => show.and_then(|s| s.title.clone()),
+            Field::Resolution => info.resolution.clone(),
+            Field::Edition => info.edition.clone(),
+            Field::ImdbId => info.imdb_id.clone(),
+            Field::TvmazeId => info.tvmaze_id.map(|i| i.to_string()),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Part {
+    Literal(String),
+    Value { field: Field, width: usize },
+    Optional(Vec<Part>),
+}
+
+/// A path template used to name sorted files, like `Season {season:02}/{title} - S{season:02}E{episode}`.
+/// Components are separated by `/` and the file extension is added automatically.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Template {
+    source: String,
+    parts: Vec<Part>,
+}
+
+impl Template {
+    pub fn parse(source: &str) -> anyhow::Result<Template> {
+        if source.trim().is_empty() {
+            bail!("Empty template");
+        }
+        if source.starts_with('/') {
+            bail!("Template must be relative: {source}");
+        }
+        if source
+            .split('/')
+            .any(|c| c.is_empty() || c == "." || c == "..")
+        {
+            bail!("Invalid path component in template: {source}");
+        }
+
+        let mut chars = source.chars().peekable();
+        let parts = Self::parse_parts(&mut chars, false)?;
+
+        Ok(Template {
+            source: source.to_string(),
+            parts,
+        })
+    }
+
+    fn parse_parts(
+        chars: &mut std::iter::Peekable<std::str::Chars>,
+        optional: bool,
+    ) -> anyhow::Result<Vec<Part>> {
+        let mut parts = vec![];
+        let mut literal = String::new();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    literal.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    literal.push('}');
+                }
+                '{' => {
+                    let mut placeholder = String::new();
+                    loop {
+                        match chars.next() {
+                            Some('}') => break,
+                            Some(c) => placeholder.push(c),
+                            None => bail!("Unclosed placeholder: {{{placeholder}"),
+                        }
+                    }
+
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    parts.push(Self::parse_value(&placeholder)?);
+                }
+                '}' => bail!("Unexpected '}}'"),
+                '<' => {
+                    if optional {
+                        bail!("Optional parts cannot be nested");
+                    }
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(std::mem::take(&mut literal)));
+                    }
+                    let inner = Self::parse_parts(chars, true)?;
+                    if !inner.iter().any(|p| matches!(p, Part::Value { .. })) {
+                        bail!("Optional part without placeholder");
+                    }
+                    parts.push(Part::Optional(inner));
+                }
+                '>' if optional => {
+                    if !literal.is_empty() {
+                        parts.push(Part::Literal(literal));
+                    }
+                    return Ok(parts);
+                }
+                '>' => bail!("Unexpected '>'"),
+                _ => literal.push(c),
+            }
+        }
+
+        if optional {
+            bail!("Unclosed optional part");
+        }
+
+        if !literal.is_empty() {
+            parts.push(Part::Literal(literal));
+        }
+
+        Ok(parts)
+    }
+
+    fn parse_value(placeholder: &str) -> anyhow::Result<Part> {
+        let (name, width) = match placeholder.split_once(':') {
+            Some((name, spec)) => match spec.strip_prefix('0').map(|w| w.parse::<usize>()) {
+                Some(Ok(w)) => (name, w),
+                _ => bail!("Invalid format in placeholder {{{placeholder}}}, expected :0N"),
+            },
+            None => (placeholder, 0),
+        };
+
+        match Field::from_name(name) {
+            Some(field) => Ok(Part::Value { field, width }),
+            None => bail!("Unknown placeholder {{{name}}}"),
+        }
+    }
+
+    /// All the values used by the template
+    pub fn fields(&self) -> Vec<Field> {
+        fn collect(parts: &[Part], fields: &mut Vec<Field>) {
+            for p in parts {
+                match p {
+                    Part::Literal(_) => {}
+                    Part::Value { field, .. } => fields.push(*field),
+                    Part::Optional(inner) => collect(inner, fields),
+                }
+            }
+        }
+
+        let mut fields = vec![];
+        collect(&self.parts, &mut fields);
+        fields
+    }
+
+    /// Check that the template only uses values from the allowed list and, if single_component
+    /// is set, that it does not create sub folders.
+    pub fn validate(
+        &self,
+        name: &str,
+        allowed: &[Field],
+        single_component: bool,
+    ) -> anyhow::Result<()> {
+        if single_component && self.source.contains('/') {
+            bail!("Template {name} cannot contain '/': {}", self.source);
+        }
+
+        if let Some(f) = self.fields().iter().find(|f| !allowed.contains(f)) {
+            bail!(
+                "Template {name} cannot use {{{}}}: {}",
+                f.name(),
+                self.source
+            );
+        }
+
+        Ok(())
+    }
+
+    /// Build the relative path for the given media.
+    /// Every value and every resulting path component is made safe with the sanitize policy.
+    pub fn render(&self, info: &MediaInfo, sanitize: Sanitize) -> anyhow::Result<PathBuf> {
+        let mut out = String::new();
+        for p in &self.parts {
+            match Self::render_part(p, info, sanitize) {
+                Some(s) => out.push_str(&s),
+                None => bail!("No value for {} in template {}", p, self.source),
+            }
+        }
+
+        Ok(out.split('/').map(|c| sanitize.component(c)).collect())
+    }
+
+    fn render_part(part: &Part, info: &MediaInfo, sanitize: Sanitize) -> Option<String> {
+        match part {
+            Part::Literal(l) => Some(l.clone()),
+            Part::Value { field, width } => {
+                let value = sanitize.value(&field.value(info)?);
+                Some(format!("{value:0>width$}"))
+            }
+            Part::Optional(inner) => Some(
+                inner
+                    .iter()
+                    .map(|p| Self::render_part(p, info, sanitize))
+                    .collect::<Option<String>>()
+                    .unwrap_or_default(),
+            ),
+        }
+    }
+}
+
+impl Display for Part {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Part::Literal(l) => write!(f, "{l}"),
+            Part::Value { field, .. } => write!(f, "{{{}}}", field.name()),
+            Part::Optional(inner) => {
+                for p in inner {
+                    write!(f, "{p}")?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl TryFrom<String> for Template {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        Template::parse(&value)
+    }
+}
+
+impl From<Template> for String {
+    fn from(value: Template) -> Self {
+        value.source
+    }
+}
+
+#[cfg(test)]
+mod naming_tests {
+    use crate::mediainfo::{Episode, MediaInfo, TVShowInfo};
+    use crate::naming::Template;
+    use crate::sanitize::Sanitize;
+    use std::path::PathBuf;
+
+    #[test]
+    fn check_render() {
+        let mut info = MediaInfo {
+            name: String::from("Great Series"),
+            year: Some(2005),
+            show_info: Some(TVShowInfo {
+                season: 3,
+                episode: Episode::Numbered(7),
+                title: None,
+            }),
+            resolution: Some(String::from("1080p")),
+            edition: None,
+            imdb_id: None,
+            tvmaze_id: Some(42),
+        };
+
+        let t = Template::parse("{title}< ({year})>/Season {season:02}/{title} - S{season:02}E{episode}< - {episode_title}>").unwrap();
+        assert_eq!(
+            t.render(&info, Sanitize::Posix).unwrap(),
+            PathBuf::from("Great Series (2005)/Season 03/Great Series - S03E07")
+        );
+
+        info.year = None;
+        let t = Template::parse("{title}< ({year})> [tvmazeid-{tvmaze_id}]").unwrap();
+        assert_eq!(
+            t.render(&info, Sanitize::Posix).unwrap(),
+            PathBuf::from("Great Series [tvmazeid-42]")
+        );
+
+        let t = Template::parse("{title} ({year})").unwrap();
+        assert!(t.render(&info, Sanitize::Posix).is_err());
+
+        let t = Template::parse("{title} {{tvmaze-{tvmaze_id}}}").unwrap();
+        assert_eq!(
+            t.render(&info, Sanitize::Posix).unwrap(),
+            PathBuf::from("Great Series {tvmaze-42}")
+        );
+    }
+
+    #[test]
+    fn check_parse_errors() {
+        assert!(Template::parse("{title").is_err());
+        assert!(Template::parse("{name}").is_err());
+        assert!(Template::parse("{season:2}").is_err());
+        assert!(Template::parse("{title}< ({year})").is_err());
+        assert!(Template::parse("<<{year}>>").is_err());
+        assert!(Template::parse("/{title}").is_err());
+        assert!(Template::parse("../{title}").is_err());
+        assert!(Template::parse("{title}<extra>").is_err());
+    }
+}
@@ -1,10 +1,12 @@
-use reqwest::Url;
+use reqwest::{Client, Url};
 use serde::{Deserialize, Serialize};
 
 // OMDB only has movies
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug)]
 pub struct OMDB {
     key: String,
+    client: Client,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -13,16 +15,19 @@ pub struct OMDBResult {
     pub title: String,
     #[serde(rename = "Year")]
     pub year: String,
+    #[serde(rename = "imdbID", default)]
+    pub imdb_id: Option<String>,
 }
 
 impl OMDB {
-    pub fn new(key: &str) -> OMDB {
+    pub fn new(key: &str, client: &Client) -> OMDB {
         OMDB {
             key: key.to_string(),
+            client: client.clone(),
         }
     }
 
-    pub fn search_movie(&self, title: &str, year: Option<i32>) -> Option<OMDBResult> {
+    pub async fn search_movie(&self, title: &str, year: Option<i32>) -> Option<OMDBResult> {
         let mut params = vec![("t", title.trim()), ("apikey", self.key.as_str())];
         let year_str;
 
@@ -33,16 +38,16 @@ impl OMDB {
 
         let url = Url::parse_with_params("http://www.omdbapi.com/", &params).unwrap();
 
-        let resp = match reqwest::blocking::get(url.as_str()) {
-            Ok(r) => match r.json::<OMDBResult>() {
+        let resp = match self.client.get(url).send().await {
+            Ok(r) => match r.json::<OMDBResult>().await {
                 Ok(j) => j,
                 Err(e) => {
-                    println!("Cannot read json response: {e}");
+                    eprintln!("Cannot read json response: {e}");
                     return None;
                 }
             },
             Err(e) => {
-                println!("Cannot get movie info: {e}");
+                eprintln!("Cannot get movie info: {e}");
                 return None;
             }
         };
@@ -0,0 +1,145 @@
+use crate::journal::Fingerprint;
+use crate::mediainfo::MediaInfo;
+use crate::report::{Action, Record};
+use crate::transfer::TransferMode;
+use anyhow::bail;
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use xxhash_rust::xxh3::xxh3_64;
+
+/// A planned operation on a file
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Step {
+    pub source: PathBuf,
+    /// The source when it was planned
+    pub fingerprint: Option<Fingerprint>,
+    /// What the file was matched to. It can be edited to fix the match, the destination is then
+    /// computed again.
+    #[serde(rename = "match")]
+    pub matched: Option<MediaInfo>,
+    /// Digest of the match when it was planned, to know if it was edited
+    #[serde(default)]
+    pub match_digest: Option<u64>,
+    /// Destination when it was planned. It is used as is, unless the match was edited.
+    pub destination: Option<PathBuf>,
+    /// Only the sort and replace steps are applied
+    pub action: Action,
+    /// Why the file cannot be sorted
+    #[serde(default)]
+    pub error: Option<String>,
+}
+
+impl Step {
+    fn from_record(record: Record) -> Step {
+        // The plan can be applied from another directory
+        let source = std::path::absolute(&record.source).unwrap_or(record.source);
+
+        Step {
+            fingerprint: Fingerprint::from_path(&source),
+            source,
+            match_digest: digest(record.matched.as_ref()),
+            matched: record.matched,
+            destination: record.destination,
+            action: record.action,
+            error: record.error,
+        }
+    }
+
+    /// Check if the match was edited since it was planned
+    pub fn is_edited(&self) -> bool {
+        digest(self.matched.as_ref()) != self.match_digest
+    }
+
+    /// Check that the source is still the planned one
+    pub fn check_source(&self) -> anyhow::Result<()> {
+        let fingerprint = match Fingerprint::from_path(&self.source) {
+            Some(f) => f,
+            None => bail!("{:?} does not exist anymore", self.source),
+        };
+
+        if self.fingerprint.as_ref() != Some(&fingerprint) {
+            bail!("{:?} changed since it was planned", self.source);
+        }
+
+        Ok(())
+    }
+}
+
+fn digest(info: Option<&MediaInfo>) -> Option<u64> {
+    let info = serde_json::to_vec(info?).ok()?;
+    Some(xxh3_64(&info))
+}
+
+/// The operations planned to sort a folder, written to a file that can be reviewed and edited
+/// before it is applied.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct Plan {
+    pub library: String,
+    pub transfer: TransferMode,
+    /// RFC 3339 time of the plan
+    pub created: String,
+    pub steps: Vec<Step>,
+}
+
+impl Plan {
+    pub fn new(library: &str, transfer: TransferMode, records: Vec<Record>) -> Plan {
+        Plan {
+            library: library.to_string(),
+            transfer,
+            created: Local::now().to_rfc3339(),
+            steps: records.into_iter().map(Step::from_record).collect(),
+        }
+    }
+
+    pub fn read(path: &Path) -> anyhow::Result<Plan> {
+        let file = File::open(path)?;
+        match serde_yaml::from_reader(file) {
+            Ok(p) => Ok(p),
+            Err(e) => bail!("Cannot load plan {path:?}: {e}"),
+        }
+    }
+
+    pub fn write(&self, path: &Path) -> anyhow::Result<()> {
+        let file = File::create(path)?;
+        serde_yaml::to_writer(file, self)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod plan_tests {
+    use crate::plan::{Plan, Step};
+    use crate::report::{Action, Record};
+    use crate::transfer::TransferMode;
+    use std::fs;
+
+    #[test]
+    fn check_plan() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let source = dir.join("Movie.2001.mkv");
+        fs::write(&source, "movie").unwrap();
+
+        let mut record = Record::new(&source, TransferMode::Copy);
+        record.destination = Some(dir.join("Movie (2001).mkv"));
+        record.matched = record.parsed.clone();
+        let plan = Plan::new("default", TransferMode::Copy, vec![record]);
+        plan.write(&dir.join("plan.yaml")).unwrap();
+
+        let plan = Plan::read(&dir.join("plan.yaml")).unwrap();
+        assert!(plan.steps[0].source.is_absolute());
+        let step: &Step = &plan.steps[0];
+        assert_eq!(step.action, Action::Sort);
+        assert!(step.check_source().is_ok());
+        assert!(!step.is_edited());
+
+        let mut edited = step.clone();
+        edited.matched.as_mut().unwrap().name = String::from("Another Movie");
+        assert!(edited.is_edited());
+
+        fs::write(&source, "another movie").unwrap();
+        assert!(step.check_source().is_err());
+    }
+}
@@ -0,0 +1,127 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use tokio::sync::{Notify, Semaphore};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct WorkersConfig {
+    /// Maximum number of concurrent TVMaze and OMDB lookups
+    #[serde(default = "WorkersConfig::default_lookups")]
+    pub lookups: usize,
+    /// Maximum number of concurrent moves, copies and links
+    #[serde(default = "WorkersConfig::default_transfers")]
+    pub transfers: usize,
+}
+
+impl WorkersConfig {
+    fn default_lookups() -> usize {
+        4
+    }
+
+    fn default_transfers() -> usize {
+        2
+    }
+}
+
+impl Default for WorkersConfig {
+    fn default() -> Self {
+        WorkersConfig {
+            lookups: Self::default_lookups(),
+            transfers: Self::default_transfers(),
+        }
+    }
+}
+
+/// Locks on paths, so that two tasks never write the same destination
+pub struct PathLocks {
+    locked: Mutex<HashSet<PathBuf>>,
+    unlocked: Notify,
+}
+
+pub struct PathLock<'a> {
+    locks: &'a PathLocks,
+    path: PathBuf,
+}
+
+impl PathLocks {
+    pub fn new() -> PathLocks {
+        PathLocks {
+            locked: Mutex::new(HashSet::new()),
+            unlocked: Notify::new(),
+        }
+    }
+
+    /// Wait until no other task holds path and lock it. It is unlocked when dropped.
+    pub async fn lock(&self, path: &Path) -> PathLock<'_> {
+        loop {
+            // Registered before checking, so that an unlock in between is not missed
+            let unlocked = self.unlocked.notified();
+
+            if self.locked.lock().unwrap().insert(path.to_path_buf()) {
+                return PathLock {
+                    locks: self,
+                    path: path.to_path_buf(),
+                };
+            }
+
+            unlocked.await;
+        }
+    }
+}
+
+impl Drop for PathLock<'_> {
+    fn drop(&mut self) {
+        self.locks.locked.lock().unwrap().remove(&self.path);
+        self.locks.unlocked.notify_waiters();
+    }
+}
+
+/// The limits shared by all the tasks processing files
+pub struct Limits {
+    pub lookups: Semaphore,
+    pub transfers: Semaphore,
+    pub destinations: PathLocks,
+}
+
+impl Limits {
+    pub fn new(config: &WorkersConfig) -> Limits {
+        Limits {
+            lookups: Semaphore::new(config.lookups.max(1)),
+            transfers: Semaphore::new(config.transfers.max(1)),
+            destinations: PathLocks::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod pool_tests {
+    use crate::pool::PathLocks;
+    use std::path::Path;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn check_destination_lock() {
+        let locks = Arc::new(PathLocks::new());
+        let writers = Arc::new(AtomicUsize::new(0));
+
+        let tasks: Vec<_> = (0..8)
+            .map(|_| {
+                let (locks, writers) = (locks.clone(), writers.clone());
+                tokio::spawn(async move {
+                    let _lock = locks.lock(Path::new("/library/movie.mkv")).await;
+                    let concurrent = writers.fetch_add(1, Ordering::SeqCst);
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                    writers.fetch_sub(1, Ordering::SeqCst);
+                    concurrent
+                })
+            })
+            .collect();
+
+        for task in tasks {
+            assert_eq!(task.await.unwrap(), 0);
+        }
+    }
+}
@@ -0,0 +1,112 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Time without new event on a path before it is passed on
+const DEBOUNCE: Duration = Duration::from_secs(1);
+
+/// Time during which events on a path that has just been processed are dropped
+const HANDLED_TTL: Duration = Duration::from_secs(60);
+
+/// Coalesce the watch events of each path: a path is only passed on once its events have stopped,
+/// and only once even if several events announced it.
+pub struct EventQueue {
+    pending: HashMap<PathBuf, Instant>,
+    handled: HashMap<PathBuf, Instant>,
+}
+
+impl EventQueue {
+    pub fn new() -> EventQueue {
+        EventQueue {
+            pending: HashMap::new(),
+            handled: HashMap::new(),
+        }
+    }
+
+    /// Record an event announcing new content at path.
+    pub fn touch(&mut self, path: &Path) {
+        if self.is_handled(path) {
+            return;
+        }
+
+        self.pending.insert(path.to_path_buf(), Instant::now());
+    }
+
+    /// Refresh the debounce of path if it is waiting, e.g. when it is still being written.
+    pub fn refresh(&mut self, path: &Path) {
+        if let Some(t) = self.pending.get_mut(path) {
+            *t = Instant::now();
+        }
+    }
+
+    /// Record that path has been removed or moved away.
+    pub fn forget(&mut self, path: &Path) {
+        self.pending.retain(|p, _| !p.starts_with(path));
+    }
+
+    /// Record that path has been moved to another path.
+    pub fn rename(&mut self, from: &Path, to: &Path) {
+        self.forget(from);
+        self.touch(to);
+    }
+
+    /// Mark path as processed: the events still coming for it are dropped.
+    pub fn handled(&mut self, path: &Path) {
+        self.pending.remove(path);
+        self.handled.insert(path.to_path_buf(), Instant::now());
+    }
+
+    pub fn is_handled(&self, path: &Path) -> bool {
+        self.handled
+            .get(path)
+            .map(|t| t.elapsed() < HANDLED_TTL)
+            .unwrap_or(false)
+    }
+
+    /// Get the paths without event for the debounce time. They are removed from the queue.
+    pub fn ready(&mut self) -> Vec<PathBuf> {
+        self.handled.retain(|_, t| t.elapsed() < HANDLED_TTL);
+
+        let mut ready: Vec<PathBuf> = self
+            .pending
+            .iter()
+            .filter(|(_, t)| t.elapsed() >= DEBOUNCE)
+            .map(|(p, _)| p.clone())
+            .collect();
+
+        for path in &ready {
+            self.pending.remove(path);
+        }
+
+        ready.sort();
+        ready
+    }
+}
+
+#[cfg(test)]
+mod queue_tests {
+    use crate::queue::EventQueue;
+    use std::path::Path;
+    use std::time::Duration;
+
+    #[test]
+    fn check_coalesce() {
+        let mut queue = EventQueue::new();
+        let (a, b, c) = (
+            Path::new("/w/a.mkv"),
+            Path::new("/w/b.mkv"),
+            Path::new("/w/c.mkv"),
+        );
+
+        queue.touch(a);
+        queue.touch(a);
+        queue.touch(b);
+        queue.rename(b, c);
+        queue.handled(a);
+        queue.touch(a);
+
+        std::thread::sleep(Duration::from_millis(1100));
+        assert_eq!(queue.ready(), vec![c.to_path_buf()]);
+        assert!(queue.ready().is_empty());
+    }
+}
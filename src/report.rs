@@ -0,0 +1,754 @@
+use crate::index::{self, Index};
+use crate::mediainfo::{Episode, MediaInfo};
+use crate::transfer::TransferMode;
+use crate::tvmaze::{EpisodeResult, TVMaze};
+use anyhow::bail;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt::{Display, Formatter};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::str::FromStr;
+
+/// Exit code when some media files could not be sorted or matched
+pub const EXIT_PARTIAL: u8 = 2;
+/// Exit code when the check found conflicts or files already in the library
+pub const EXIT_CONFLICTS: u8 = 3;
+
+/// Format of the reports
+#[derive(Clone, Copy, Eq, PartialEq, Debug)]
+pub enum Output {
+    Table,
+    Json,
+    /// One JSON record per line
+    Ndjson,
+    Csv,
+}
+
+impl FromStr for Output {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(Output::Table),
+            "json" => Ok(Output::Json),
+            "ndjson" => Ok(Output::Ndjson),
+            "csv" => Ok(Output::Csv),
+            _ => bail!("Unknown output format: {s}"),
+        }
+    }
+}
+
+/// What is done with a file
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Debug)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    /// Put in the library with the transfer mode
+    Sort,
+    /// Put in the library in place of an existing file
+    Replace,
+    /// Not sorted: not a media file, or its destination already exists
+    Skip,
+    /// Would be sorted to the same destination as another checked file, or the same episode or
+    /// movie is already in the library under another name
+    Conflict,
+    /// Could not be sorted
+    Fail,
+}
+
+impl Display for Action {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Action::Sort => write!(f, "sort"),
+            Action::Replace => write!(f, "replace"),
+            Action::Skip => write!(f, "skip"),
+            Action::Conflict => write!(f, "conflict"),
+            Action::Fail => write!(f, "fail"),
+        }
+    }
+}
+
+/// What was done, or would be done, with a sorted or checked file
+#[derive(Serialize)]
+pub struct Record {
+    pub source: PathBuf,
+    /// What the file name was parsed to
+    pub parsed: Option<MediaInfo>,
+    /// What the file was matched to online
+    #[serde(rename = "match")]
+    pub matched: Option<MediaInfo>,
+    pub destination: Option<PathBuf>,
+    pub transfer: TransferMode,
+    pub action: Action,
+    pub error: Option<String>,
+    /// The same episode or movie already in the library under another name
+    #[serde(skip)]
+    pub duplicate: Option<PathBuf>,
+}
+
+impl Record {
+    pub fn new(source: &Path, transfer: TransferMode) -> Record {
+        Record {
+            source: source.to_path_buf(),
+            parsed: MediaInfo::is_media(source).then(|| MediaInfo::extract_media_info(source)),
+            matched: None,
+            destination: None,
+            transfer,
+            action: Action::Sort,
+            error: None,
+            duplicate: None,
+        }
+    }
+
+    /// Record the error that stopped the sort. Files that are not media files are skipped.
+    pub fn fail(&mut self, error: anyhow::Error) {
+        if !matches!(self.action, Action::Skip | Action::Conflict) {
+            self.action = match MediaInfo::is_media(&self.source)
+                || MediaInfo::is_disc_folder(&self.source)
+            {
+                true => Action::Fail,
+                false => Action::Skip,
+            };
+        }
+        self.error = Some(error.to_string());
+    }
+
+    /// Check if the record is a media file that was not sorted
+    fn is_failure(&self) -> bool {
+        matches!(self.action, Action::Fail | Action::Conflict)
+            || (self.action == Action::Skip && self.destination.is_some())
+    }
+}
+
+/// Get the exit code of a sort: EXIT_PARTIAL if some media files were not sorted
+pub fn sort_exit_code(records: &[Record]) -> ExitCode {
+    match records.iter().any(Record::is_failure) {
+        true => ExitCode::from(EXIT_PARTIAL),
+        false => ExitCode::SUCCESS,
+    }
+}
+
+/// Print what was done with the sorted files
+pub fn print_sorted(records: &[Record], output: Output) -> anyhow::Result<()> {
+    if output != Output::Table {
+        return print_records(records, output);
+    }
+
+    for record in records {
+        match (&record.error, &record.destination) {
+            (None, Some(p)) => println!("Sorted {:?} to {p:?}", record.source),
+            (e, _) => println!(
+                "Cannot sort {:?}: {}",
+                record.source,
+                e.as_deref().unwrap_or_default()
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Quote a CSV field if needed
+fn csv_field(value: &str) -> String {
+    match value.contains([',', '"', '\n', '\r']) {
+        true => format!("\"{}\"", value.replace('"', "\"\"")),
+        false => value.to_string(),
+    }
+}
+
+/// Columns of a media info in the CSV output
+fn csv_info(info: Option<&MediaInfo>) -> Vec<String> {
+    let show = info.and_then(|i| i.show_info.as_ref());
+    vec![
+        info.map(|i| i.name.clone()).unwrap_or_default(),
+        info.and_then(|i| i.year)
+            .map(|y| y.to_string())
+            .unwrap_or_default(),
+        show.map(|s| s.season.to_string()).unwrap_or_default(),
+        show.map(|s| s.episode.to_string()).unwrap_or_default(),
+    ]
+}
+
+/// Print one record per file in the given machine readable format
+pub fn print_records(records: &[Record], output: Output) -> anyhow::Result<()> {
+    match output {
+        Output::Table => bail!("Records have no table output"),
+        Output::Json => println!("{}", serde_json::to_string_pretty(records)?),
+        Output::Ndjson => {
+            for record in records {
+                println!("{}", serde_json::to_string(record)?);
+            }
+        }
+        Output::Csv => {
+            println!(
+                "source,parsed_title,parsed_year,parsed_season,parsed_episode,\
+                match_title,match_year,match_season,match_episode,match_episode_title,\
+                match_imdb_id,match_tvmaze_id,destination,transfer,action,error"
+            );
+            for record in records {
+                let matched = record.matched.as_ref();
+                let mut fields = vec![record.source.to_string_lossy().into_owned()];
+                fields.extend(csv_info(record.parsed.as_ref()));
+                fields.extend(csv_info(matched));
+                fields.extend([
+                    matched
+                        .and_then(|i| i.show_info.as_ref())
+                        .and_then(|s| s.title.clone())
+                        .unwrap_or_default(),
+                    matched.and_then(|i| i.imdb_id.clone()).unwrap_or_default(),
+                    matched
+                        .and_then(|i| i.tvmaze_id)
+                        .map(|i| i.to_string())
+                        .unwrap_or_default(),
+                    record
+                        .destination
+                        .as_ref()
+                        .map(|d| d.to_string_lossy().into_owned())
+                        .unwrap_or_default(),
+                    record.transfer.to_string(),
+                    record.action.to_string(),
+                    record.error.clone().unwrap_or_default(),
+                ]);
+
+                let fields: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+                println!("{}", fields.join(","));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct MissingEpisode {
+    pub episode: u32,
+    pub title: String,
+    pub airdate: String,
+}
+
+/// The aired episodes of a show that are not in its folder
+#[derive(Serialize)]
+pub struct ShowReport {
+    pub show: String,
+    pub path: PathBuf,
+    pub tvmaze_id: Option<u64>,
+    /// Missing episodes, by season
+    pub missing: BTreeMap<u16, Vec<MissingEpisode>>,
+    /// Why the show could not be checked
+    pub error: Option<String>,
+}
+
+/// A show folder of a library with its episodes on TVMaze
+pub struct ShowEpisodes {
+    pub show: String,
+    pub path: PathBuf,
+    pub tvmaze_id: Option<u64>,
+    pub episodes: Vec<EpisodeResult>,
+    /// Why the episodes could not be found
+    pub error: Option<String>,
+}
+
+/// Get the show folders of a library
+pub fn show_folders(show_path: &Path) -> Vec<PathBuf> {
+    let mut folders: Vec<PathBuf> = match show_path.read_dir() {
+        Ok(entries) => entries
+            .flatten()
+            .map(|e| e.path())
+            .filter(|p| p.is_dir())
+            .collect(),
+        Err(e) => {
+            eprintln!("Cannot read {show_path:?}: {e}");
+            vec![]
+        }
+    };
+
+    folders.sort();
+    folders
+}
+
+/// Get the episode list on TVMaze of the show in the given folder.
+/// The show is identified by a "[tvmazeid-N]" tag in the folder name or else searched by the
+/// title of the folder.
+pub async fn show_episodes(folder: &Path, tvmaze: &TVMaze) -> ShowEpisodes {
+    let name = folder.file_name().unwrap_or_default().to_string_lossy();
+    let (title, year) = MediaInfo::library_title(&name);
+    let (_, tvmaze_id) = MediaInfo::library_ids(Path::new(name.as_ref()));
+
+    let mut show = ShowEpisodes {
+        show: title.clone(),
+        path: folder.to_path_buf(),
+        tvmaze_id,
+        episodes: vec![],
+        error: None,
+    };
+
+    let id = match tvmaze_id {
+        Some(id) => id,
+        None => match tvmaze.search_show(&title, year).await {
+            Some(res) => res.show.id,
+            None => {
+                show.error = Some(String::from("Show not found"));
+                return show;
+            }
+        },
+    };
+    show.tvmaze_id = Some(id);
+
+    match tvmaze.episodes(id).await {
+        Some(e) => show.episodes = e,
+        None => show.error = Some(String::from("Cannot get the episodes")),
+    }
+
+    show
+}
+
+/// Compare the show with the files of its folder. Only the episodes aired before today are
+/// reported.
+pub fn missing_episodes(show: ShowEpisodes, today: NaiveDate) -> ShowReport {
+    let present = present_episodes(&show.path);
+
+    ShowReport {
+        missing: missing(show.episodes, &present, today),
+        show: show.show,
+        path: show.path,
+        tvmaze_id: show.tvmaze_id,
+        error: show.error,
+    }
+}
+
+/// Get the episodes aired before today that are not present, by season
+fn missing(
+    episodes: Vec<EpisodeResult>,
+    present: &HashSet<(u16, u32)>,
+    today: NaiveDate,
+) -> BTreeMap<u16, Vec<MissingEpisode>> {
+    let mut missing: BTreeMap<u16, Vec<MissingEpisode>> = BTreeMap::new();
+
+    for episode in episodes {
+        let number = match episode.number {
+            Some(n) => n,
+            None => continue,
+        };
+        let airdate = episode.airdate.unwrap_or_default();
+        let aired = matches!(
+            NaiveDate::parse_from_str(&airdate, "%Y-%m-%d"),
+            Ok(d) if d < today
+        );
+
+        if aired && !present.contains(&(episode.season, number)) {
+            missing
+                .entry(episode.season)
+                .or_default()
+                .push(MissingEpisode {
+                    episode: number,
+                    title: episode.name,
+                    airdate,
+                });
+        }
+    }
+
+    missing
+}
+
+/// Get the season and episode numbers of the files in a show folder
+pub fn present_episodes(folder: &Path) -> HashSet<(u16, u32)> {
+    let mut present = HashSet::new();
+    let entries = match folder.read_dir() {
+        Ok(e) => e,
+        Err(_) => return present,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.is_dir() {
+            present.extend(present_episodes(&path));
+        } else if MediaInfo::is_media(&path) {
+            if let Some(show) = MediaInfo::extract_media_info(&path).show_info {
+                if let Episode::Numbered(e) = show.episode {
+                    present.insert((show.season.into(), e.into()));
+                }
+            }
+        }
+    }
+
+    present
+}
+
+pub fn print_missing(reports: &[ShowReport], output: Output) -> anyhow::Result<()> {
+    match output {
+        Output::Json => {
+            println!("{}", serde_json::to_string_pretty(reports)?);
+            return Ok(());
+        }
+        Output::Ndjson => {
+            for report in reports {
+                println!("{}", serde_json::to_string(report)?);
+            }
+            return Ok(());
+        }
+        Output::Csv => bail!("The missing episodes have no csv output"),
+        Output::Table => {}
+    }
+
+    for report in reports {
+        if let Some(e) = &report.error {
+            println!("{}: {e}", report.show);
+            continue;
+        }
+
+        let count: usize = report.missing.values().map(Vec::len).sum();
+        if count == 0 {
+            continue;
+        }
+
+        println!("{}: {count} missing episodes", report.show);
+        for (season, episodes) in &report.missing {
+            println!("  Season {season:02}");
+            for e in episodes {
+                println!(
+                    "    S{season:02}E{:02}  {:10}  {}",
+                    e.episode, e.airdate, e.title
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Resolution and size of a file or of a disc structure
+#[derive(Serialize)]
+pub struct Quality {
+    pub resolution: Option<String>,
+    pub size: u64,
+}
+
+impl Quality {
+    /// Read the quality of the given path. The resolution comes from the index, or else from the
+    /// name.
+    pub fn of(path: &Path, index: Option<&Index>) -> Quality {
+        let indexed = index.and_then(|i| i.get(path).ok().flatten());
+        let resolution = match indexed {
+            Some(item) if item.info.resolution.is_some() => item.info.resolution,
+            _ => MediaInfo::extract_media_info(path).resolution,
+        };
+
+        Quality {
+            resolution,
+            size: index::size(path),
+        }
+    }
+
+    /// Vertical resolution, e.g. 1080 for "1080p"
+    fn lines(&self) -> Option<u32> {
+        self.resolution
+            .as_deref()?
+            .trim_end_matches('p')
+            .parse()
+            .ok()
+    }
+
+    /// Describe how this quality compares to the other one: by resolution, or else by size
+    pub fn compare(&self, other: &Quality) -> &'static str {
+        let ordering = match (self.lines(), other.lines()) {
+            (Some(a), Some(b)) if a != b => a.cmp(&b),
+            (Some(_), Some(_)) | (None, None) => self.size.cmp(&other.size),
+            _ => return "unknown difference",
+        };
+
+        match ordering {
+            Ordering::Greater => "better",
+            Ordering::Less => "worse",
+            Ordering::Equal => "same",
+        }
+    }
+}
+
+impl Display for Quality {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} ({} bytes)",
+            self.resolution.as_deref().unwrap_or("unknown resolution"),
+            self.size
+        )
+    }
+}
+
+/// A checked file whose destination is already in the library
+#[derive(Serialize)]
+pub struct Existing {
+    pub source: PathBuf,
+    pub destination: PathBuf,
+    /// The library overwrites existing files, otherwise the file would be skipped
+    pub overwrite: bool,
+    pub source_quality: Quality,
+    pub library_quality: Quality,
+}
+
+impl Existing {
+    fn new(source: &Path, destination: &Path, overwrite: bool, index: Option<&Index>) -> Existing {
+        Existing {
+            source: source.to_path_buf(),
+            destination: destination.to_path_buf(),
+            overwrite,
+            source_quality: Quality::of(source, None),
+            library_quality: Quality::of(destination, index),
+        }
+    }
+}
+
+/// Result of a check of a folder, by category
+#[derive(Serialize, Default)]
+pub struct CheckReport {
+    /// Files that would be sorted to the same destination, or whose episode or movie is already
+    /// in the library under another name, by destination
+    pub conflicts: BTreeMap<PathBuf, Vec<PathBuf>>,
+    /// Files whose destination is already in the library
+    pub existing: Vec<Existing>,
+    /// Files that could not be matched, with the reason
+    pub unmatched: Vec<(PathBuf, String)>,
+    /// Files that are not media files
+    pub skipped: Vec<PathBuf>,
+}
+
+impl CheckReport {
+    /// Build the report from the records of the checked files. The index gives the resolution
+    /// of the files already in the library, when their names do not.
+    pub fn new(records: &[Record], index: Option<&Index>) -> CheckReport {
+        let mut report = CheckReport::default();
+        let mut destinations: BTreeMap<PathBuf, Vec<PathBuf>> = BTreeMap::new();
+
+        for record in records {
+            let source = &record.source;
+            match (record.action, &record.destination) {
+                (Action::Sort | Action::Replace, Some(dst)) => {
+                    if record.action == Action::Replace {
+                        report
+                            .existing
+                            .push(Existing::new(source, dst, true, index));
+                    }
+                    destinations
+                        .entry(dst.clone())
+                        .or_default()
+                        .push(source.clone())
+                }
+                (Action::Skip, Some(dst)) => {
+                    report
+                        .existing
+                        .push(Existing::new(source, dst, false, index));
+                }
+                (Action::Skip, None) => report.skipped.push(source.clone()),
+                (Action::Conflict, Some(dst)) => {
+                    let items = destinations.entry(dst.clone()).or_default();
+                    for item in record.duplicate.iter().chain([source]) {
+                        if !items.contains(item) {
+                            items.push(item.clone());
+                        }
+                    }
+                }
+                _ => report
+                    .unmatched
+                    .push((source.clone(), record.error.clone().unwrap_or_default())),
+            }
+        }
+
+        report.conflicts = destinations
+            .into_iter()
+            .filter(|(_, items)| items.len() > 1)
+            .collect();
+        report
+    }
+
+    /// Mark the records of the conflicting files
+    pub fn mark_conflicts(&self, records: &mut [Record]) {
+        for record in records {
+            if matches!(&record.destination, Some(d) if self.conflicts.contains_key(d))
+                && record.action != Action::Skip
+            {
+                record.action = Action::Conflict;
+            }
+        }
+    }
+
+    /// Get the exit code of the check: EXIT_PARTIAL if some media files cannot be matched, else
+    /// EXIT_CONFLICTS if some files conflict or are already in the library.
+    pub fn exit_code(&self) -> ExitCode {
+        if !self.unmatched.is_empty() {
+            ExitCode::from(EXIT_PARTIAL)
+        } else if !self.conflicts.is_empty() || !self.existing.is_empty() {
+            ExitCode::from(EXIT_CONFLICTS)
+        } else {
+            ExitCode::SUCCESS
+        }
+    }
+
+    pub fn print(&self) {
+        println!("Conflicts: {}", self.conflicts.len());
+        for (dst, items) in &self.conflicts {
+            println!("  {dst:?}");
+            for item in items {
+                println!("    {item:?}");
+            }
+        }
+
+        println!("Already in the library: {}", self.existing.len());
+        for existing in &self.existing {
+            println!(
+                "  {:?} -> {:?}: {}",
+                existing.source,
+                existing.destination,
+                match existing.overwrite {
+                    true => "would be replaced",
+                    false => "would be skipped",
+                }
+            );
+            println!(
+                "    {} vs {} in the library: {}",
+                existing.source_quality,
+                existing.library_quality,
+                existing.source_quality.compare(&existing.library_quality)
+            );
+        }
+
+        println!("Unmatched: {}", self.unmatched.len());
+        for (item, reason) in &self.unmatched {
+            println!("  {item:?}: {reason}");
+        }
+
+        println!("Skipped extensions: {}", self.skipped.len());
+        for item in &self.skipped {
+            println!("  {item:?}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod report_tests {
+    use crate::report::{
+        csv_field, missing, sort_exit_code, Action, CheckReport, Record, EXIT_PARTIAL,
+    };
+    use crate::transfer::TransferMode;
+    use crate::tvmaze::EpisodeResult;
+    use anyhow::anyhow;
+    use chrono::NaiveDate;
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+    use std::process::ExitCode;
+
+    #[test]
+    fn check_missing() {
+        let episode = |season, number, airdate: &str| EpisodeResult {
+            id: None,
+            name: String::from("Title"),
+            season,
+            number,
+            airdate: Some(airdate.to_string()),
+            airstamp: None,
+            runtime: None,
+        };
+        let episodes = vec![
+            episode(1, Some(1), "2020-01-01"),
+            episode(1, Some(2), "2020-01-08"),
+            episode(1, None, "2020-01-10"),
+            episode(2, Some(1), "2021-01-01"),
+            episode(2, Some(2), "2030-01-01"),
+            episode(2, Some(3), ""),
+        ];
+        let present = HashSet::from([(1, 1)]);
+
+        let missing = missing(
+            episodes,
+            &present,
+            NaiveDate::from_ymd_opt(2024, 1, 1).unwrap(),
+        );
+        let numbers: Vec<(u16, Vec<u32>)> = missing
+            .iter()
+            .map(|(s, e)| (*s, e.iter().map(|e| e.episode).collect()))
+            .collect();
+        assert_eq!(numbers, vec![(1, vec![2]), (2, vec![1])]);
+
+        // Daily shows are numbered by year and long running ones have hundreds of episodes
+        let episode: EpisodeResult =
+            serde_json::from_str(r#"{"name": "Title", "season": 2024, "number": 1000}"#).unwrap();
+        assert_eq!((episode.season, episode.number), (2024, Some(1000)));
+    }
+
+    #[test]
+    fn check_check_report() {
+        let dst = PathBuf::from("/shows/The Show/Season 01/The Show - S01E01.mkv");
+        let record = |source: &str, destination: Option<&PathBuf>, error: Option<&str>| {
+            let mut record = Record::new(Path::new(source), TransferMode::Move);
+            record.destination = destination.cloned();
+            if let Some(e) = error {
+                if destination.is_some() {
+                    record.action = Action::Skip;
+                }
+                record.fail(anyhow!(e.to_string()));
+            }
+            record
+        };
+
+        let mut records = vec![
+            record("/a/The.Show.S01E01.720p.mkv", Some(&dst), None),
+            record("/b/The.Show.S01E01.1080p.mkv", Some(&dst), None),
+            record(
+                "/a/The.Show.S01E02.mkv",
+                Some(&PathBuf::from("/shows/The Show - S01E02.mkv")),
+                None,
+            ),
+            record("/a/Unknown.S01E01.mkv", None, Some("Show not found")),
+            record("/a/readme.txt", None, Some("Unknown extension")),
+            record(
+                "/a/The.Show.S01E03.1080p.mkv",
+                Some(&PathBuf::from("/shows/The Show - S01E03.720p.mkv")),
+                Some("Already exists"),
+            ),
+        ];
+        let mut duplicate = record(
+            "/a/The.Show.S01E04.mp4",
+            Some(&PathBuf::from("/shows/The Show - S01E04.mp4")),
+            None,
+        );
+        duplicate.action = Action::Conflict;
+        duplicate.duplicate = Some(PathBuf::from("/shows/The Show - S01E04.mkv"));
+        duplicate.fail(anyhow!("Already in the library"));
+        records.push(duplicate);
+        let report = CheckReport::new(&records, None);
+
+        assert_eq!(report.conflicts.len(), 2);
+        assert_eq!(report.conflicts[&dst].len(), 2);
+        assert_eq!(
+            report.conflicts[&PathBuf::from("/shows/The Show - S01E04.mp4")],
+            vec![
+                PathBuf::from("/shows/The Show - S01E04.mkv"),
+                PathBuf::from("/a/The.Show.S01E04.mp4")
+            ]
+        );
+        assert_eq!(report.unmatched.len(), 1);
+        assert_eq!(report.skipped, vec![PathBuf::from("/a/readme.txt")]);
+
+        let existing = &report.existing[0];
+        assert!(!existing.overwrite);
+        assert_eq!(
+            existing.source_quality.compare(&existing.library_quality),
+            "better"
+        );
+
+        report.mark_conflicts(&mut records);
+        assert_eq!(records[0].action, Action::Conflict);
+        assert_eq!(records[2].action, Action::Sort);
+        assert_eq!(records[5].action, Action::Skip);
+        assert_eq!(records[6].action, Action::Conflict);
+        assert_eq!(report.exit_code(), ExitCode::from(EXIT_PARTIAL));
+        assert_eq!(sort_exit_code(&records), ExitCode::from(EXIT_PARTIAL));
+    }
+
+    #[test]
+    fn check_csv_field() {
+        assert_eq!(csv_field("a.mkv"), "a.mkv");
+        assert_eq!(csv_field("Say \"Hi\", Bob"), "\"Say \"\"Hi\"\", Bob\"");
+    }
+}
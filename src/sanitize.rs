@@ -0,0 +1,102 @@
+use serde::{Deserialize, Serialize};
+
+/// Names that cannot be used as file names on Windows, whatever the extension
+const WINDOWS_RESERVED: [&str; 22] = [
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// Characters policy applied to the names of the sorted files and folders.
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Sanitize {
+    /// Only remove what POSIX forbids: '/' and NUL
+    #[default]
+    Posix,
+    /// Names that are valid on Windows and SMB shares
+    Windows,
+    /// Same as Windows, with non ASCII characters transliterated
+    Ascii,
+}
+
+impl Sanitize {
+    /// Make a value (like a title) safe to be inserted in a path component.
+    /// Path separators are replaced so that a value never creates sub folders.
+    pub fn value(&self, value: &str) -> String {
+        let value = match self {
+            Sanitize::Ascii => deunicode::deunicode(value),
+            _ => value.to_string(),
+        };
+
+        match self {
+            Sanitize::Posix => value.replace('\0', "").replace('/', "-"),
+            Sanitize::Windows | Sanitize::Ascii => Self::windows_chars(&value),
+        }
+    }
+
+    /// Make a full path component valid, once the values have been inserted.
+    pub fn component(&self, component: &str) -> String {
+        let mut component = self.value(component);
+
+        if *self != Sanitize::Posix {
+            // Windows silently drops trailing dots and spaces
+            component = component.trim_end_matches(['.', ' ']).to_string();
+
+            let stem = component.split('.').next().unwrap_or_default();
+            if WINDOWS_RESERVED.contains(&stem.to_ascii_uppercase().as_str()) {
+                component.insert(stem.len(), '_');
+            }
+        }
+
+        if component.is_empty() || component == "." || component == ".." {
+            component = "_".to_string();
+        }
+
+        component
+    }
+
+    fn windows_chars(value: &str) -> String {
+        let mut out = String::with_capacity(value.len());
+        let mut chars = value.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                // "Mission: Impossible" -> "Mission - Impossible"
+                ':' if chars.peek() == Some(&' ') => out.push_str(" -"),
+                ':' | '/' | '\\' | '|' => out.push('-'),
+                '"' => out.push('\''),
+                '<' => out.push('('),
+                '>' => out.push(')'),
+                '?' | '*' => {}
+                c if c.is_control() => {}
+                c => out.push(c),
+            }
+        }
+
+        out
+    }
+}
+
+#[cfg(test)]
+mod sanitize_tests {
+    use crate::sanitize::Sanitize;
+
+    #[test]
+    fn check_sanitize() {
+        assert_eq!(
+            Sanitize::Posix.value("AC/DC: Let There Be Rock"),
+            "AC-DC: Let There Be Rock"
+        );
+        assert_eq!(
+            Sanitize::Windows.value("AC/DC: Let There Be Rock"),
+            "AC-DC - Let There Be Rock"
+        );
+        assert_eq!(Sanitize::Windows.component("What If...?"), "What If");
+        assert_eq!(Sanitize::Windows.component("Con.mkv"), "Con_.mkv");
+        assert_eq!(Sanitize::Posix.component("Con"), "Con");
+        assert_eq!(
+            Sanitize::Ascii.component("Amélie: \"Le Fabuleux Destin\""),
+            "Amelie - 'Le Fabuleux Destin'"
+        );
+    }
+}
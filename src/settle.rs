@@ -0,0 +1,241 @@
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+#[derive(Serialize, Deserialize, Clone)]
+pub struct SettleConfig {
+    /// Number of seconds the size and modification time of a file must be stable before it is
+    /// sorted
+    #[serde(default = "SettleConfig::default_time")]
+    pub time: u64,
+    /// Extensions used by download clients for incomplete files. These files are never sorted,
+    /// the client renames them once complete.
+    #[serde(default = "SettleConfig::default_partial_extensions")]
+    pub partial_extensions: Vec<String>,
+}
+
+impl SettleConfig {
+    fn default_time() -> u64 {
+        10
+    }
+
+    fn default_partial_extensions() -> Vec<String> {
+        ["part", "!qB", "crdownload", "partial", "tmp", "aria2"]
+            .iter()
+            .map(|e| e.to_string())
+            .collect()
+    }
+
+    /// Check if the given path is an incomplete download
+    pub fn is_partial(&self, path: &Path) -> bool {
+        match path.extension() {
+            Some(ext) => self
+                .partial_extensions
+                .iter()
+                .any(|p| ext.eq_ignore_ascii_case(p.as_str())),
+            None => false,
+        }
+    }
+}
+
+impl Default for SettleConfig {
+    fn default() -> Self {
+        SettleConfig {
+            time: Self::default_time(),
+            partial_extensions: Self::default_partial_extensions(),
+        }
+    }
+}
+
+/// Size and last modification of a file or of a whole tree
+#[derive(Eq, PartialEq, Clone, Copy)]
+struct Snapshot {
+    size: u64,
+    modified: SystemTime,
+}
+
+impl Snapshot {
+    fn take(path: &Path) -> Option<Snapshot> {
+        let meta = fs::metadata(path).ok()?;
+        let mut snapshot = Snapshot {
+            size: meta.len(),
+            modified: meta.modified().ok()?,
+        };
+
+        if meta.is_dir() {
+            for entry in path.read_dir().ok()?.flatten() {
+                let s = Self::take(&entry.path())?;
+                snapshot.size += s.size;
+                snapshot.modified = snapshot.modified.max(s.modified);
+            }
+        }
+
+        Some(snapshot)
+    }
+}
+
+struct Pending {
+    snapshot: Snapshot,
+    since: Instant,
+}
+
+/// Paths waiting for their content to be complete before being sorted
+pub struct SettleQueue {
+    config: SettleConfig,
+    pending: HashMap<PathBuf, Pending>,
+}
+
+impl SettleQueue {
+    pub fn new(config: SettleConfig) -> SettleQueue {
+        SettleQueue {
+            config,
+            pending: HashMap::new(),
+        }
+    }
+
+    /// Wait for the given path to settle. Adding a path that is already waiting restarts its
+    /// wait.
+    pub fn add(&mut self, path: &Path) {
+        if self.config.is_partial(path) {
+            eprintln!("Incomplete download: {path:?}. Ignoring...");
+            return;
+        }
+
+        match Snapshot::take(path) {
+            Some(snapshot) => {
+                self.pending.insert(
+                    path.to_path_buf(),
+                    Pending {
+                        snapshot,
+                        since: Instant::now(),
+                    },
+                );
+            }
+            None => {
+                self.pending.remove(path);
+            }
+        }
+    }
+
+    /// Get the paths that are complete: stable for the configured time and not opened for
+    /// writing anymore. They are removed from the queue.
+    pub fn ready(&mut self) -> Vec<PathBuf> {
+        let settle_time = Duration::from_secs(self.config.time);
+        let mut ready = vec![];
+        let mut gone = vec![];
+
+        for (path, pending) in self.pending.iter_mut() {
+            let snapshot = match Snapshot::take(path) {
+                Some(s) => s,
+                None => {
+                    gone.push(path.clone());
+                    continue;
+                }
+            };
+
+            if snapshot != pending.snapshot {
+                pending.snapshot = snapshot;
+                pending.since = Instant::now();
+            } else if pending.since.elapsed() >= settle_time {
+                if is_open_for_writing(path) {
+                    pending.since = Instant::now();
+                } else {
+                    ready.push(path.clone());
+                }
+            }
+        }
+
+        for path in gone.iter().chain(ready.iter()) {
+            self.pending.remove(path);
+        }
+
+        ready.sort();
+        ready
+    }
+}
+
+/// Check if any process has the given file, or a file in the given folder, opened for writing.
+/// Processes that cannot be inspected are ignored.
+pub fn is_open_for_writing(path: &Path) -> bool {
+    let procs = match Path::new("/proc").read_dir() {
+        Ok(p) => p,
+        Err(_) => return false,
+    };
+
+    for proc in procs.flatten() {
+        let fds = match proc.path().join("fd").read_dir() {
+            Ok(f) => f,
+            Err(_) => continue,
+        };
+
+        for fd in fds.flatten() {
+            let target = match fs::read_link(fd.path()) {
+                Ok(t) => t,
+                Err(_) => continue,
+            };
+            if !target.starts_with(path) {
+                continue;
+            }
+
+            let fdinfo = proc.path().join("fdinfo").join(fd.file_name());
+            if let Ok(info) = fs::read_to_string(fdinfo) {
+                let flags = info
+                    .lines()
+                    .find_map(|l| l.strip_prefix("flags:"))
+                    .and_then(|f| u32::from_str_radix(f.trim(), 8).ok())
+                    .unwrap_or(0);
+                if flags & (libc::O_WRONLY | libc::O_RDWR) as u32 != 0 {
+                    return true;
+                }
+            }
+        }
+    }
+
+    false
+}
+
+#[cfg(test)]
+mod settle_tests {
+    use crate::settle::{SettleConfig, SettleQueue};
+    use std::fs;
+    use std::io::Write;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    #[test]
+    fn check_settle() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let mut queue = SettleQueue::new(SettleConfig {
+            time: 1,
+            ..SettleConfig::default()
+        });
+
+        for name in ["Movie.mkv.part", "Movie.mkv.!qB", "Movie.mkv.PART"] {
+            fs::write(dir.join(name), "partial").unwrap();
+            queue.add(&dir.join(name));
+        }
+        assert!(queue.pending.is_empty());
+
+        let file = dir.join("Movie.mkv");
+        fs::write(&file, "movie").unwrap();
+        queue.add(&file);
+        assert!(queue.ready().is_empty());
+
+        // Still written: the wait restarts
+        sleep(Duration::from_millis(700));
+        let mut f = fs::OpenOptions::new().append(true).open(&file).unwrap();
+        f.write_all(b" and more").unwrap();
+        drop(f);
+        assert!(queue.ready().is_empty());
+
+        sleep(Duration::from_millis(700));
+        assert!(queue.ready().is_empty());
+
+        sleep(Duration::from_millis(400));
+        assert_eq!(queue.ready(), vec![file]);
+        assert!(queue.pending.is_empty());
+    }
+}
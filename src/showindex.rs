@@ -0,0 +1,164 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{LazyLock, Mutex};
+use std::time::SystemTime;
+
+static TAGS: LazyLock<Regex> = LazyLock::new(|| Regex::new(r"\[[^\]]*\]|\{[^}]*\}").unwrap());
+static YEAR: LazyLock<Regex> =
+    LazyLock::new(|| Regex::new(r"^(?P<title>.*?)\s*\((?P<year>\d{4})\)\s*$").unwrap());
+
+/// A show folder found in the library
+struct ShowFolder {
+    path: PathBuf,
+    key: String,
+    year: Option<i32>,
+}
+
+/// Index of the existing show folders, to reuse a folder even if its name differs in case,
+/// punctuation or year suffix from the name that would be generated.
+pub struct ShowIndex {
+    folders: Vec<ShowFolder>,
+    /// Modification time of the show path when it was read
+    modified: Option<SystemTime>,
+}
+
+impl ShowIndex {
+    pub fn new(show_path: &Path) -> ShowIndex {
+        let mut folders = vec![];
+        let modified = Self::modified(show_path);
+
+        if let Ok(entries) = show_path.read_dir() {
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if !path.is_dir() {
+                    continue;
+                }
+
+                let name = entry.file_name().to_string_lossy().to_string();
+                let (key, year) = Self::normalize(&name);
+                folders.push(ShowFolder { path, key, year });
+            }
+        }
+
+        folders.sort_by(|a, b| a.path.cmp(&b.path));
+
+        ShowIndex { folders, modified }
+    }
+
+    fn modified(show_path: &Path) -> Option<SystemTime> {
+        show_path.metadata().and_then(|m| m.modified()).ok()
+    }
+
+    /// Check if a folder was created or removed in the show path since it was read
+    fn is_stale(&self, show_path: &Path) -> bool {
+        Self::modified(show_path) != self.modified
+    }
+
+    /// Find the existing folder of the given show.
+    /// A folder with a different year is never a match. If several folders match, a warning is
+    /// printed and the one with the same year is preferred.
+    pub fn find(&self, name: &str, year: Option<i32>) -> Option<PathBuf> {
+        let (key, _) = Self::normalize(name);
+        let candidates: Vec<&ShowFolder> = self
+            .folders
+            .iter()
+            .filter(|f| f.key == key)
+            .filter(|f| f.year.is_none() || year.is_none() || f.year == year)
+            .collect();
+
+        if candidates.len() > 1 {
+            eprintln!(
+                "Warning: several folders match {name} ({}): {:?}",
+                year.unwrap_or(-1),
+                candidates.iter().map(|f| &f.path).collect::<Vec<_>>()
+            );
+        }
+
+        candidates
+            .iter()
+            .find(|f| year.is_some() && f.year == year)
+            .or(candidates.first())
+            .map(|f| f.path.clone())
+    }
+
+    /// Get the key used to compare show names and the year found in the name, if any.
+    /// "The Office (US) (2005) [tvdbid-73244]" gives ("the office us", Some(2005))
+    pub fn normalize(name: &str) -> (String, Option<i32>) {
+        let name = TAGS.replace_all(name, "").trim().to_string();
+        let (title, year) = match YEAR.captures(&name) {
+            Some(c) => (c["title"].to_string(), c["year"].parse().ok()),
+            None => (name, None),
+        };
+
+        let key = deunicode::deunicode(&title)
+            .to_lowercase()
+            .replace('&', " and ")
+            .chars()
+            .filter(|c| !matches!(c, '\'' | '.'))
+            .map(|c| if c.is_alphanumeric() { c } else { ' ' })
+            .collect::<String>()
+            .split_whitespace()
+            .collect::<Vec<&str>>()
+            .join(" ");
+
+        (key, year)
+    }
+}
+
+/// The show indexes of the libraries, read again when a show folder is created or removed
+#[derive(Default)]
+pub struct ShowIndexes {
+    indexes: Mutex<HashMap<PathBuf, ShowIndex>>,
+}
+
+impl ShowIndexes {
+    /// Find the existing folder of the given show in show_path, see ShowIndex::find
+    pub fn find(&self, show_path: &Path, name: &str, year: Option<i32>) -> Option<PathBuf> {
+        let mut indexes = self.indexes.lock().unwrap();
+        let index = indexes
+            .entry(show_path.to_path_buf())
+            .or_insert_with(|| ShowIndex::new(show_path));
+        if index.is_stale(show_path) {
+            *index = ShowIndex::new(show_path);
+        }
+
+        index.find(name, year)
+    }
+}
+
+#[cfg(test)]
+mod showindex_tests {
+    use crate::showindex::{ShowIndex, ShowIndexes};
+    use std::fs;
+
+    #[test]
+    fn check_normalize() {
+        assert_eq!(
+            ShowIndex::normalize("The Office (US) (2005) [tvdbid-73244]"),
+            (String::from("the office us"), Some(2005))
+        );
+        assert_eq!(
+            ShowIndex::normalize("Marvel's Agents of S.H.I.E.L.D."),
+            ShowIndex::normalize("Marvels Agents of SHIELD")
+        );
+        assert_eq!(
+            ShowIndex::normalize("Law & Order: SVU"),
+            (String::from("law and order svu"), None)
+        );
+    }
+
+    #[test]
+    fn check_refresh() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let shows = ShowIndexes::default();
+        assert_eq!(shows.find(dir, "The Office", Some(2005)), None);
+
+        fs::create_dir(dir.join("The Office (2005)")).unwrap();
+        assert_eq!(
+            shows.find(dir, "The Office", Some(2005)),
+            Some(dir.join("The Office (2005)"))
+        );
+    }
+}
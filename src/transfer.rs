@@ -0,0 +1,724 @@
+use crate::config::PermissionConfig;
+use anyhow::bail;
+use libc::c_char;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::RandomState;
+use std::ffi::{CString, OsString};
+use std::fmt::{Display, Formatter};
+use std::fs;
+use std::fs::FileTimes;
+use std::hash::BuildHasher;
+use std::io::Read;
+use std::os::fd::AsRawFd;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::sync::LazyLock;
+use xxhash_rust::xxh3::Xxh3;
+
+/// Prefix of the temporary names used while copying files to the library
+const PARTIAL_PREFIX: &str = ".media-sort-rs.partial.";
+
+/// Random token of the process in the temporary names, so that they never collide with the
+/// leftovers of a previous run, even with the same process id (e.g. in a container)
+static RUN_TOKEN: LazyLock<String> =
+    LazyLock::new(|| format!("{:016x}", RandomState::new().hash_one(std::process::id())));
+
+/// How files are put in the library
+#[derive(Serialize, Deserialize, Clone, Copy, Eq, PartialEq, Debug, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum TransferMode {
+    #[default]
+    Move,
+    Copy,
+    /// Hard link the files, copy them if the library is on another file system
+    Hardlink,
+    Symlink,
+    /// Copy on write clone of the files, copy them if the file system does not support it
+    Reflink,
+}
+
+impl TransferMode {
+    /// Check if the given destination file is shared with the source: the permissions of linked
+    /// files must not be changed. Hard links that fell back to a copy belong to the library.
+    fn is_shared(&self, dst: &Path) -> bool {
+        match self {
+            TransferMode::Symlink => true,
+            TransferMode::Hardlink => fs::metadata(dst).map(|m| m.nlink() > 1).unwrap_or(false),
+            _ => false,
+        }
+    }
+}
+
+impl Display for TransferMode {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TransferMode::Move => write!(f, "move"),
+            TransferMode::Copy => write!(f, "copy"),
+            TransferMode::Hardlink => write!(f, "hardlink"),
+            TransferMode::Symlink => write!(f, "symlink"),
+            TransferMode::Reflink => write!(f, "reflink"),
+        }
+    }
+}
+
+/// Put src at dst with the given mode. src can be a file or a whole directory tree (e.g. a DVD
+/// structure).
+/// Copies are written to a temporary name in the destination folder, synced and optionally
+/// verified before being renamed to dst, so that dst is never a partial copy.
+/// If overwrite is false, an existing dst is never replaced, even if it appears while the
+/// transfer is running: an error is returned instead. If it is true, the existing dst is only
+/// removed once src is in place.
+pub fn transfer(
+    src: &Path,
+    dst: &Path,
+    mode: TransferMode,
+    verify: bool,
+    overwrite: bool,
+) -> anyhow::Result<()> {
+    if let Some(parent) = dst.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let res = match mode {
+        TransferMode::Move => move_path(src, dst, verify, overwrite),
+        TransferMode::Symlink => symlink_path(src, dst, overwrite),
+        _ => copy_tree(src, dst, mode, verify, overwrite),
+    };
+
+    match res {
+        Err(e) if is_conflict(&e) => Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("{dst:?} already exists: Skipping"),
+        )
+        .into()),
+        res => res,
+    }
+}
+
+/// Check if the transfer failed because dst already exists and could not be replaced
+pub fn is_conflict(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<std::io::Error>()
+        .map(|e| e.kind() == std::io::ErrorKind::AlreadyExists)
+        .unwrap_or(false)
+}
+
+/// Check if a and b are links to the same file
+fn is_same_file(a: &Path, b: &Path) -> bool {
+    match (fs::symlink_metadata(a), fs::symlink_metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+/// Rename src to dst, failing if dst exists and overwrite is false.
+fn rename(src: &Path, dst: &Path, overwrite: bool) -> std::io::Result<()> {
+    if overwrite {
+        // rename does nothing if both are links to the same file
+        if is_same_file(src, dst) {
+            return fs::remove_file(src);
+        }
+
+        // rename cannot replace a non empty directory, or a file by a directory
+        return match fs::rename(src, dst) {
+            Err(e)
+                if matches!(
+                    e.raw_os_error(),
+                    Some(libc::ENOTEMPTY | libc::EEXIST | libc::EISDIR | libc::ENOTDIR)
+                ) =>
+            {
+                replace(src, dst)
+            }
+            res => res,
+        };
+    }
+
+    let c_src = CString::new(src.as_os_str().as_bytes())?;
+    let c_dst = CString::new(dst.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            c_src.as_ptr(),
+            libc::AT_FDCWD,
+            c_dst.as_ptr(),
+            libc::RENAME_NOREPLACE,
+        )
+    };
+    if ret == 0 {
+        return Ok(());
+    }
+
+    let err = std::io::Error::last_os_error();
+    if !matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) {
+        return Err(err);
+    }
+
+    // The file system does not support RENAME_NOREPLACE
+    if src.is_dir() {
+        // Reserve the name: a directory can be renamed over an empty one.
+        fs::create_dir(dst)?;
+        fs::rename(src, dst).inspect_err(|_| {
+            let _ = fs::remove_dir(dst);
+        })
+    } else {
+        // link() never replaces an existing file
+        fs::hard_link(src, dst)?;
+        fs::remove_file(src)
+    }
+}
+
+/// Put src in place of the existing dst, then remove the old dst: dst is never missing.
+fn replace(src: &Path, dst: &Path) -> std::io::Result<()> {
+    let c_src = CString::new(src.as_os_str().as_bytes())?;
+    let c_dst = CString::new(dst.as_os_str().as_bytes())?;
+    let ret = unsafe {
+        libc::renameat2(
+            libc::AT_FDCWD,
+            c_src.as_ptr(),
+            libc::AT_FDCWD,
+            c_dst.as_ptr(),
+            libc::RENAME_EXCHANGE,
+        )
+    };
+
+    // The old dst is now at src
+    let old = if ret == 0 {
+        src.to_path_buf()
+    } else {
+        let err = std::io::Error::last_os_error();
+        if !matches!(err.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) {
+            return Err(err);
+        }
+
+        // The file system does not support RENAME_EXCHANGE: the old dst is renamed aside first,
+        // it is removed as a partial copy if the process stops before the end.
+        let mut name = OsString::from(format!("{PARTIAL_PREFIX}{}.old.", std::process::id()));
+        name.push(dst.file_name().unwrap_or_default());
+        let aside = dst.with_file_name(name);
+
+        fs::rename(dst, &aside)?;
+        if let Err(e) = fs::rename(src, dst) {
+            let _ = fs::rename(&aside, dst);
+            return Err(e);
+        }
+        aside
+    };
+
+    remove_partial(&old);
+    Ok(())
+}
+
+/// Symlink src to dst. The link is created under a temporary name, then renamed to dst.
+fn symlink_path(src: &Path, dst: &Path, overwrite: bool) -> anyhow::Result<()> {
+    let tmp = partial_path(dst);
+    std::os::unix::fs::symlink(src.canonicalize()?, &tmp)?;
+
+    rename(&tmp, dst, overwrite).map_err(|e| {
+        let _ = fs::remove_file(&tmp);
+        e.into()
+    })
+}
+
+/// Move src to dst.
+/// If a rename is not possible (e.g. src and dst are on different file systems), the tree is
+/// copied and the source removed once the copy is complete.
+fn move_path(src: &Path, dst: &Path, verify: bool, overwrite: bool) -> anyhow::Result<()> {
+    // A replaced dst is exchanged with src, then removed from there
+    match rename(src, dst, overwrite) {
+        Ok(_) => return Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => return Err(e.into()),
+        _ => {}
+    }
+
+    // Maybe src and dst are on different FS, try to copy instead.
+    copy_tree(src, dst, TransferMode::Copy, verify, overwrite)?;
+    if src.is_dir() {
+        fs::remove_dir_all(src)?;
+    } else {
+        fs::remove_file(src)?;
+    }
+
+    Ok(())
+}
+
+/// Get the temporary name used while dst is being written. It is unique to the process.
+fn partial_path(dst: &Path) -> PathBuf {
+    let mut name = OsString::from(format!("{PARTIAL_PREFIX}{}.", *RUN_TOKEN));
+    name.push(dst.file_name().unwrap_or_default());
+    dst.with_file_name(name)
+}
+
+/// Copy, hard link or reflink the src tree to a temporary name, then rename it to dst.
+/// The temporary tree is locked while it is written, so that it is not cleaned up.
+fn copy_tree(
+    src: &Path,
+    dst: &Path,
+    mode: TransferMode,
+    verify: bool,
+    overwrite: bool,
+) -> anyhow::Result<()> {
+    let tmp = partial_path(dst);
+
+    let res = build_tree(src, &tmp, mode).and_then(|_lock| {
+        if verify {
+            verify_tree(src, &tmp)?;
+        }
+        rename(&tmp, dst, overwrite)?;
+        sync_parent(dst)
+    });
+
+    if res.is_err() {
+        remove_partial(&tmp);
+    }
+
+    res
+}
+
+/// Build the dst tree from src. Returns dst opened and locked.
+fn build_tree(src: &Path, dst: &Path, mode: TransferMode) -> anyhow::Result<fs::File> {
+    if !src.is_dir() {
+        return copy_file(src, dst, mode);
+    }
+
+    fs::create_dir(dst)?;
+    let dir = fs::File::open(dst)?;
+    lock(&dir, true)?;
+    for entry in src.read_dir()? {
+        let entry = entry?;
+        build_tree(&entry.path(), &dst.join(entry.file_name()), mode)?;
+    }
+
+    let meta = fs::metadata(src)?;
+    dir.set_times(file_times(&meta)?)?;
+
+    Ok(dir)
+}
+
+/// Copy src to dst. Returns dst opened and locked.
+fn copy_file(src: &Path, dst: &Path, mode: TransferMode) -> anyhow::Result<fs::File> {
+    if mode == TransferMode::Hardlink {
+        match fs::hard_link(src, dst) {
+            Ok(_) => {
+                let file = fs::File::open(dst)?;
+                lock(&file, true)?;
+                return Ok(file);
+            }
+            // Different file systems, fall back to a copy
+            Err(e) if e.raw_os_error() == Some(libc::EXDEV) => {}
+            Err(e) => return Err(e.into()),
+        }
+    }
+
+    let meta = fs::metadata(src)?;
+    let mut src_file = fs::File::open(src)?;
+    let mut dst_file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst)?;
+    lock(&dst_file, true)?;
+
+    // Fall back to a copy if the file system does not support reflinks
+    if mode != TransferMode::Reflink || reflink(&src_file, &dst_file).is_err() {
+        std::io::copy(&mut src_file, &mut dst_file)?;
+    }
+
+    dst_file.set_permissions(meta.permissions())?;
+    dst_file.set_times(file_times(&meta)?)?;
+    dst_file.sync_all()?;
+
+    Ok(dst_file)
+}
+
+/// Take an exclusive lock on the given file or folder. If wait is false and it is already
+/// locked, an error of kind WouldBlock is returned.
+fn lock(file: &fs::File, wait: bool) -> std::io::Result<()> {
+    let operation = match wait {
+        true => libc::LOCK_EX,
+        false => libc::LOCK_EX | libc::LOCK_NB,
+    };
+    match unsafe { libc::flock(file.as_raw_fd(), operation) } {
+        0 => Ok(()),
+        _ => Err(std::io::Error::last_os_error()),
+    }
+}
+
+fn file_times(meta: &fs::Metadata) -> std::io::Result<FileTimes> {
+    Ok(FileTimes::new()
+        .set_accessed(meta.accessed()?)
+        .set_modified(meta.modified()?))
+}
+
+fn reflink(src: &fs::File, dst: &fs::File) -> std::io::Result<()> {
+    let ret = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if ret != 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Make the rename in the parent folder durable
+fn sync_parent(path: &Path) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent() {
+        fs::File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+/// Compare the checksums of all the files of both trees
+fn verify_tree(src: &Path, dst: &Path) -> anyhow::Result<()> {
+    if src.is_dir() {
+        for entry in src.read_dir()? {
+            let entry = entry?;
+            verify_tree(&entry.path(), &dst.join(entry.file_name()))?;
+        }
+
+        return Ok(());
+    }
+
+    let (src_meta, dst_meta) = (fs::metadata(src)?, fs::metadata(dst)?);
+    if src_meta.dev() == dst_meta.dev() && src_meta.ino() == dst_meta.ino() {
+        // Hard link: same file
+        return Ok(());
+    }
+
+    if src_meta.len() != dst_meta.len() || checksum(src)? != checksum(dst)? {
+        bail!("Verification of the copy of {src:?} failed");
+    }
+
+    Ok(())
+}
+
+/// Compute the checksum of a file content
+pub fn checksum(path: &Path) -> std::io::Result<u128> {
+    let mut file = fs::File::open(path)?;
+    let mut hasher = Xxh3::new();
+    let mut buf = vec![0; 1024 * 1024];
+
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+
+    Ok(hasher.digest128())
+}
+
+fn remove_partial(path: &Path) {
+    let res = if path.is_dir() {
+        fs::remove_dir_all(path)
+    } else {
+        fs::remove_file(path)
+    };
+
+    if let Err(e) = res {
+        eprintln!("Cannot remove partial copy {path:?}: {e}");
+    }
+}
+
+/// Check if the given partial copy is being written: its writer holds a lock on it
+fn is_locked(path: &Path) -> bool {
+    match fs::File::open(path) {
+        Ok(file) => lock(&file, false).is_err(),
+        Err(_) => false,
+    }
+}
+
+/// Remove the partial copies left in root by an interrupted transfer. The copies being written
+/// by running processes are left alone.
+/// If dry_run is true, they are only listed.
+pub fn cleanup_partials(root: &Path, dry_run: bool) {
+    let entries = match root.read_dir() {
+        Ok(e) => e,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let is_dir = entry.file_type().map(|t| t.is_dir()).unwrap_or(false);
+
+        let name = entry.file_name().to_string_lossy().to_string();
+        if name.starts_with(PARTIAL_PREFIX) {
+            if is_locked(&path) {
+                continue;
+            }
+            eprintln!("Removing interrupted copy {path:?}");
+            if !dry_run {
+                remove_partial(&path);
+            }
+        } else if is_dir {
+            cleanup_partials(&path, dry_run);
+        }
+    }
+}
+
+/// Set the configured permissions on dst and on all its parents up to root (excluded).
+/// If dst is a directory, its whole content gets the permissions too.
+/// Files shared with the source by the transfer mode (links) are left untouched, only the folders
+/// of the library are changed.
+pub fn apply_permissions(
+    dst: &Path,
+    root: &Path,
+    perms: &PermissionConfig,
+    mode: TransferMode,
+) -> anyhow::Result<()> {
+    let owner = lookup_user(&perms.user);
+    let mut path = dst.to_path_buf();
+
+    if mode == TransferMode::Symlink {
+        if let Some((uid, gid)) = owner {
+            if let Err(e) = std::os::unix::fs::lchown(dst, Some(uid), Some(gid)) {
+                eprintln!("Cannot change owner of {dst:?}: {e}");
+            }
+        }
+        path.pop();
+    } else if dst.is_dir() {
+        set_tree_permissions(dst, perms, owner, mode)?;
+    } else if mode.is_shared(dst) {
+        path.pop();
+    }
+
+    while path != root && path.starts_with(root) {
+        set_permissions(&path, perms, owner)?;
+        path.pop();
+    }
+
+    Ok(())
+}
+
+fn set_tree_permissions(
+    dir: &Path,
+    perms: &PermissionConfig,
+    owner: Option<(u32, u32)>,
+    mode: TransferMode,
+) -> anyhow::Result<()> {
+    for entry in dir.read_dir()? {
+        let path = entry?.path();
+        if path.is_dir() {
+            set_tree_permissions(&path, perms, owner, mode)?;
+        } else if mode.is_shared(&path) {
+            continue;
+        }
+        set_permissions(&path, perms, owner)?;
+    }
+
+    Ok(())
+}
+
+fn set_permissions(
+    path: &Path,
+    perms: &PermissionConfig,
+    owner: Option<(u32, u32)>,
+) -> anyhow::Result<()> {
+    let mut p = fs::metadata(path)?.permissions();
+    let mode = perms.mode + if path.is_dir() { 0o111 } else { 0 };
+    p.set_mode(mode);
+    fs::set_permissions(path, p)?;
+
+    if let Some((uid, gid)) = owner {
+        if let Err(e) = std::os::unix::fs::chown(path, Some(uid), Some(gid)) {
+            eprintln!("Cannot change owner of {path:?}: {e}");
+        }
+    }
+
+    Ok(())
+}
+
+/// Get the uid and primary gid of the given user name.
+fn lookup_user(user: &str) -> Option<(u32, u32)> {
+    let name = CString::new(user).ok()?;
+
+    unsafe {
+        let pwd = libc::getpwnam(name.as_ptr() as *const c_char);
+        if pwd.is_null() {
+            None
+        } else {
+            Some(((*pwd).pw_uid, (*pwd).pw_gid))
+        }
+    }
+}
+
+#[cfg(test)]
+mod transfer_tests {
+    use crate::config::PermissionConfig;
+    use crate::transfer::{
+        apply_permissions, cleanup_partials, copy_tree, is_conflict, lock, partial_path, transfer,
+        TransferMode, PARTIAL_PREFIX,
+    };
+    use std::fs;
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+
+    #[test]
+    fn check_replace_disc() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for mode in [TransferMode::Move, TransferMode::Copy] {
+            let (src, dst) = (
+                dir.join(format!("new-{mode}")),
+                dir.join(format!("old-{mode}")),
+            );
+            fs::create_dir_all(src.join("VIDEO_TS")).unwrap();
+            fs::create_dir_all(dst.join("VIDEO_TS")).unwrap();
+            fs::write(src.join("VIDEO_TS/VTS_01_1.VOB"), "new").unwrap();
+            fs::write(dst.join("VIDEO_TS/VTS_01_2.VOB"), "old").unwrap();
+
+            transfer(&src, &dst, mode, false, true).unwrap();
+            assert_eq!(
+                fs::read_to_string(dst.join("VIDEO_TS/VTS_01_1.VOB")).unwrap(),
+                "new"
+            );
+            assert!(!dst.join("VIDEO_TS/VTS_01_2.VOB").exists());
+            assert_eq!(src.exists(), mode == TransferMode::Copy);
+        }
+
+        // Nothing left next to the destinations
+        let mut names: Vec<String> = fs::read_dir(dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().to_string_lossy().to_string())
+            .collect();
+        names.sort();
+        assert_eq!(names, vec!["new-copy", "old-copy", "old-move"]);
+    }
+
+    fn permissions(path: &Path) -> u32 {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    #[test]
+    fn check_modes() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for mode in [
+            TransferMode::Move,
+            TransferMode::Copy,
+            TransferMode::Hardlink,
+            TransferMode::Symlink,
+        ] {
+            let src = dir.join(format!("{mode}.mkv"));
+            let dst = dir.join(format!("library/{mode}/Movie.mkv"));
+            fs::write(&src, "movie").unwrap();
+
+            transfer(&src, &dst, mode, true, false).unwrap();
+            assert_eq!(fs::read_to_string(&dst).unwrap(), "movie");
+            assert_eq!(src.exists(), mode != TransferMode::Move);
+            if mode == TransferMode::Hardlink {
+                assert_eq!(
+                    fs::metadata(&src).unwrap().ino(),
+                    fs::metadata(&dst).unwrap().ino()
+                );
+            }
+            assert!(!partial_path(&dst).exists());
+        }
+    }
+
+    #[test]
+    fn check_no_overwrite() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        for mode in [TransferMode::Move, TransferMode::Copy] {
+            let src = dir.join(format!("new-{mode}.mkv"));
+            let dst = dir.join(format!("old-{mode}.mkv"));
+            fs::write(&src, "new").unwrap();
+            fs::write(&dst, "old").unwrap();
+
+            let e = transfer(&src, &dst, mode, false, false).unwrap_err();
+            assert!(is_conflict(&e));
+            assert_eq!(fs::read_to_string(&src).unwrap(), "new");
+            assert_eq!(fs::read_to_string(&dst).unwrap(), "old");
+            assert!(!partial_path(&dst).exists());
+        }
+    }
+
+    #[test]
+    fn check_failed_verify() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let dst = dir.join("status");
+
+        // procfs reports an empty size for files with a content: the copy never matches
+        assert!(copy_tree(
+            Path::new("/proc/self/status"),
+            &dst,
+            TransferMode::Copy,
+            true,
+            false
+        )
+        .is_err());
+        assert!(!dst.exists());
+        assert!(!partial_path(&dst).exists());
+    }
+
+    #[test]
+    fn check_cleanup_partials() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        // Left by a previous run of this process, which gets the same id in a container
+        let dead = dir
+            .join("Show")
+            .join(partial_path(Path::new("Episode.mkv")));
+        let disc = dir.join(format!("{PARTIAL_PREFIX}0123456789abcdef.Disc"));
+        let running = partial_path(&dir.join("Movie.mkv"));
+        fs::create_dir_all(dir.join("Show")).unwrap();
+        fs::create_dir_all(disc.join("VIDEO_TS")).unwrap();
+        fs::write(&dead, "partial").unwrap();
+        fs::write(&running, "partial").unwrap();
+        let file = fs::File::open(&running).unwrap();
+        lock(&file, true).unwrap();
+
+        cleanup_partials(dir, true);
+        assert!(dead.exists() && disc.exists());
+
+        cleanup_partials(dir, false);
+        assert!(!dead.exists());
+        assert!(!disc.exists());
+        assert!(running.exists());
+    }
+
+    #[test]
+    fn check_linked_permissions() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let perms = PermissionConfig {
+            mode: 0o644,
+            user: String::new(),
+            group: String::new(),
+        };
+
+        let src = dir.join("Movie.mkv");
+        fs::write(&src, "movie").unwrap();
+        fs::set_permissions(&src, fs::Permissions::from_mode(0o600)).unwrap();
+
+        let library = dir.join("library");
+        for mode in [TransferMode::Hardlink, TransferMode::Copy] {
+            let dst = library.join(format!("{mode}/Movie.mkv"));
+            transfer(&src, &dst, mode, false, false).unwrap();
+            apply_permissions(&dst, &library, &perms, mode).unwrap();
+            assert_eq!(permissions(dst.parent().unwrap()), 0o755);
+        }
+
+        assert_eq!(permissions(&src), 0o600);
+        assert_eq!(permissions(&library.join("copy/Movie.mkv")), 0o644);
+    }
+
+    #[test]
+    fn check_relink() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path();
+        let (src, dst) = (dir.join("Movie.2001.mkv"), dir.join("Movie (2001).mkv"));
+        fs::write(&src, "movie").unwrap();
+
+        // Sorted again while already linked in the library
+        transfer(&src, &dst, TransferMode::Hardlink, false, true).unwrap();
+        transfer(&src, &dst, TransferMode::Hardlink, false, true).unwrap();
+        assert!(src.exists() && dst.exists());
+        assert!(!partial_path(&dst).exists());
+
+        transfer(&src, &dst, TransferMode::Move, false, true).unwrap();
+        assert!(!src.exists());
+        assert_eq!(fs::read_to_string(&dst).unwrap(), "movie");
+    }
+}
@@ -1,13 +1,44 @@
-use reqwest::Url;
+use reqwest::{Client, Url};
 use serde::{Deserialize, Serialize};
 
 // TVMaze only has shows
 #[derive(Debug)]
-pub struct TVMaze;
+pub struct TVMaze {
+    client: Client,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Externals {
+    pub imdb: Option<String>,
+}
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ShowResult {
+    pub id: u64,
+    pub name: String,
+    #[serde(default)]
+    pub externals: Externals,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EpisodeResult {
+    #[serde(default)]
+    pub id: Option<u64>,
     pub name: String,
+    #[serde(default)]
+    pub season: u16,
+    /// Not set for specials
+    #[serde(default)]
+    pub number: Option<u32>,
+    /// "YYYY-MM-DD", empty or not set if unknown
+    #[serde(default)]
+    pub airdate: Option<String>,
+    /// RFC 3339 air time, if known
+    #[serde(default)]
+    pub airstamp: Option<String>,
+    /// In minutes
+    #[serde(default)]
+    pub runtime: Option<u32>,
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -17,29 +48,79 @@ pub struct SearchResult {
 }
 
 impl TVMaze {
-    pub fn search_show(title: &str, _year: Option<i32>) -> Option<SearchResult> {
+    pub fn new(client: &Client) -> TVMaze {
+        TVMaze {
+            client: client.clone(),
+        }
+    }
+
+    pub async fn search_show(&self, title: &str, _year: Option<i32>) -> Option<SearchResult> {
         let url =
             Url::parse_with_params("http://api.tvmaze.com/search/shows", &[("q", title.trim())])
                 .unwrap();
 
-        let resp = match reqwest::blocking::get(url.as_str()) {
-            Ok(r) => match r.json::<Vec<SearchResult>>() {
+        let resp = match self.client.get(url).send().await {
+            Ok(r) => match r.json::<Vec<SearchResult>>().await {
                 Ok(j) => j,
                 Err(e) => {
-                    println!("Cannot read json response: {e}");
+                    eprintln!("Cannot read json response: {e}");
                     return None;
                 }
             },
             Err(e) => {
-                println!("Cannot get show info: {e}");
+                eprintln!("Cannot get show info: {e}");
                 return None;
             }
         };
 
-        return if resp.is_empty() {
+        if resp.is_empty() {
             None
         } else {
             Some(resp[0].clone())
-        };
+        }
+    }
+
+    pub async fn episode_title(&self, show_id: u64, season: u8, episode: u8) -> Option<String> {
+        let url = Url::parse_with_params(
+            &format!("http://api.tvmaze.com/shows/{show_id}/episodebynumber"),
+            &[
+                ("season", season.to_string()),
+                ("number", episode.to_string()),
+            ],
+        )
+        .unwrap();
+
+        match self.client.get(url).send().await {
+            Ok(r) => match r.json::<EpisodeResult>().await {
+                Ok(j) => Some(j.name),
+                Err(e) => {
+                    eprintln!("Cannot read json response: {e}");
+                    None
+                }
+            },
+            Err(e) => {
+                eprintln!("Cannot get episode info: {e}");
+                None
+            }
+        }
+    }
+
+    /// Get the episodes of a show, without the specials
+    pub async fn episodes(&self, show_id: u64) -> Option<Vec<EpisodeResult>> {
+        let url = format!("http://api.tvmaze.com/shows/{show_id}/episodes");
+
+        match self.client.get(url).send().await {
+            Ok(r) => match r.json::<Vec<EpisodeResult>>().await {
+                Ok(j) => Some(j),
+                Err(e) => {
+                    eprintln!("Cannot read json response: {e}");
+                    None
+                }
+            },
+            Err(e) => {
+                eprintln!("Cannot get the episodes: {e}");
+                None
+            }
+        }
     }
 }
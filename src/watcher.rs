@@ -0,0 +1,168 @@
+use crate::config::Config;
+use notify::{
+    Event, INotifyWatcher, PollWatcher, RecommendedWatcher, RecursiveMode, Result, Watcher,
+};
+use std::collections::HashMap;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::UnboundedSender;
+
+/// First delay before trying to re-establish lost watches
+const MIN_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Maximum delay between two attempts to re-establish lost watches
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+/// The watches of all the watched folders of the libraries.
+/// Folders are watched with inotify, or polled if configured so or if inotify fails. Their events
+/// are sent to the async event stream.
+pub struct Watchers {
+    tx: UnboundedSender<Result<Event>>,
+    inotify: Option<INotifyWatcher>,
+    pollers: Vec<PollWatcher>,
+    /// Device and inode of each watched folder when it was watched
+    roots: HashMap<PathBuf, (u64, u64)>,
+    healthy: bool,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl Watchers {
+    pub fn new(tx: UnboundedSender<Result<Event>>) -> Watchers {
+        Watchers {
+            tx,
+            inotify: None,
+            pollers: vec![],
+            roots: HashMap::new(),
+            healthy: false,
+            backoff: MIN_BACKOFF,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// Mark the watches as lost: they will be re-established by the next call to recover.
+    pub fn lost(&mut self) {
+        if self.healthy {
+            self.healthy = false;
+            self.backoff = MIN_BACKOFF;
+            self.next_attempt = Instant::now() + self.backoff;
+        }
+    }
+
+    /// Check that all the watched folders are still the watched ones, e.g. they were not deleted,
+    /// unmounted or remounted: an unmounted mount point is still a folder, on another device.
+    pub fn check(&mut self) {
+        if !self.healthy {
+            return;
+        }
+
+        if let Some(path) = self
+            .roots
+            .iter()
+            .find(|(path, id)| Self::identity(path).as_ref() != Some(*id))
+            .map(|(path, _)| path)
+        {
+            eprintln!("The watched folder {path:?} disappeared or changed");
+            self.lost();
+        }
+    }
+
+    /// Get the device and the inode of a folder
+    fn identity(path: &Path) -> Option<(u64, u64)> {
+        path.metadata()
+            .ok()
+            .filter(|m| m.is_dir())
+            .map(|m| (m.dev(), m.ino()))
+    }
+
+    /// Try to re-establish the watches if they are lost and it is time for a new attempt.
+    /// Returns true if the watches have just been re-established.
+    pub fn recover(&mut self, config: &Config) -> bool {
+        if self.healthy || Instant::now() < self.next_attempt {
+            return false;
+        }
+
+        let backoff = self.backoff;
+        match self.establish(config) {
+            Ok(_) if self.healthy => {
+                eprintln!("All watched folders are watched again");
+                return true;
+            }
+            Ok(_) => {}
+            Err(e) => eprintln!("Cannot watch the folders: {e}"),
+        }
+
+        eprintln!("Retrying in {}s", backoff.as_secs());
+        self.next_attempt = Instant::now() + backoff;
+        self.backoff = (backoff * 2).min(MAX_BACKOFF);
+        false
+    }
+
+    fn handler(&self) -> impl FnMut(Result<Event>) + Send + 'static {
+        let tx = self.tx.clone();
+        move |res| {
+            // The receiver is only dropped when the service stops
+            let _ = tx.send(res);
+        }
+    }
+
+    /// (Re-)create all the watches. The missing folders are reported and left unwatched: the
+    /// watches are then not healthy. An error is returned if a watcher cannot be created.
+    pub fn establish(&mut self, config: &Config) -> anyhow::Result<()> {
+        self.inotify = None;
+        self.pollers.clear();
+        self.roots.clear();
+        self.healthy = false;
+
+        let mut inotify = RecommendedWatcher::new(self.handler(), notify::Config::default())?;
+        let mut missing = vec![];
+
+        for dir in config.libraries.iter().flat_map(|l| l.dir_watch.iter()) {
+            match Self::identity(&dir.path) {
+                Some(id) => self.roots.insert(dir.path.clone(), id),
+                None => {
+                    missing.push(dir.path.clone());
+                    continue;
+                }
+            };
+
+            let interval = match dir.poll {
+                Some(interval) => interval,
+                None => match inotify.watch(&dir.path, RecursiveMode::Recursive) {
+                    Ok(_) => continue,
+                    Err(e) => {
+                        // e.g. max_user_watches is exhausted
+                        let _ = inotify.unwatch(&dir.path);
+                        let interval = Duration::from_secs(config.poll_interval);
+                        eprintln!(
+                            "Cannot watch {:?}: {e}. Polling it every {}s instead",
+                            dir.path,
+                            interval.as_secs()
+                        );
+                        interval
+                    }
+                },
+            };
+
+            let mut poller = PollWatcher::new(
+                self.handler(),
+                notify::Config::default().with_poll_interval(interval),
+            )?;
+            poller.watch(&dir.path, RecursiveMode::Recursive)?;
+            self.pollers.push(poller);
+        }
+
+        self.inotify = Some(inotify);
+
+        if !missing.is_empty() {
+            eprintln!("Missing watched folders: {missing:?}");
+            return Ok(());
+        }
+
+        self.healthy = true;
+        self.backoff = MIN_BACKOFF;
+
+        Ok(())
+    }
+}